@@ -9,6 +9,8 @@ extern crate kernel;
 extern crate sam4l;
 
 use capsules::alarm::AlarmDriver;
+use capsules::ieee802154::{AGGREGATE_BUF_LEN, PATTERN_BUF_LEN, RX_HOLD_BUF_LEN,
+                           TX_QUEUE_BUF_LEN};
 use capsules::ieee802154::mac::Mac;
 use capsules::rf233::RF233;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
@@ -63,7 +65,8 @@ struct Imix {
     spi: &'static capsules::spi::Spi<'static, VirtualSpiMasterDevice<'static, sam4l::spi::Spi>>,
     ipc: kernel::ipc::IPC,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
-    radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
+    radio_driver: &'static capsules::ieee802154::RadioDriver<'static,
+                        VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
     usb_driver: &'static capsules::usb_user::UsbSyscallDriver<'static,
                         capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>>,
@@ -82,12 +85,23 @@ struct Imix {
 
 static mut RF233_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 static mut RF233_RX_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+// The MAC layer receives into a second buffer while it processes a frame.
+static mut RF233_RX_SPARE_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 static mut RF233_REG_WRITE: [u8; 2] = [0x00; 2];
 static mut RF233_REG_READ: [u8; 2] = [0x00; 2];
 // The RF233 system call interface ("radio") requires one buffer, which it
 // copies application transmissions into or copies out to application buffers
 // for reception.
 static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
+// It also holds received frames for apps that fetch them on demand, in buffers
+// shared by all apps.
+static mut RADIO_RX_HOLD_BUF: [u8; 2 * RX_HOLD_BUF_LEN] = [0x00; 2 * RX_HOLD_BUF_LEN];
+// One app can send and receive aggregated frames at a time.
+static mut RADIO_AGGREGATE_BUF: [u8; 2 * AGGREGATE_BUF_LEN] = [0x00; 2 * AGGREGATE_BUF_LEN];
+// Up to 4 transmissions can be queued behind the pending ones of all apps.
+static mut RADIO_TX_QUEUE_BUF: [u8; 4 * TX_QUEUE_BUF_LEN] = [0x00; 4 * TX_QUEUE_BUF_LEN];
+// Each app can filter received frames by a payload pattern.
+static mut RADIO_PATTERN_BUF: [u8; 2 * PATTERN_BUF_LEN] = [0x00; 2 * PATTERN_BUF_LEN];
 
 impl kernel::Platform for Imix {
     fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
@@ -425,28 +439,47 @@ pub unsafe fn reset_handler() {
     rf233.set_transmit_client(rf233_mac);
     rf233.set_receive_client(rf233_mac, &mut RF233_RX_BUF);
     rf233.set_config_client(rf233_mac);
+    rf233.set_measurement_client(rf233_mac);
+    rf233.set_power_client(rf233_mac);
+    rf233_mac.set_spare_receive_buffer(&mut RF233_RX_SPARE_BUF);
 
     let mux_mac = static_init!(
         capsules::ieee802154::virtual_mac::MuxMac<'static>,
         capsules::ieee802154::virtual_mac::MuxMac::new(rf233_mac));
     rf233_mac.set_transmit_client(mux_mac);
     rf233_mac.set_receive_client(mux_mac);
+    rf233_mac.set_measurement_client(mux_mac);
+    rf233_mac.set_power_client(mux_mac);
+    rf233_mac.set_config_client(mux_mac);
 
     let radio_mac = static_init!(
         capsules::ieee802154::virtual_mac::MacUser<'static>,
         capsules::ieee802154::virtual_mac::MacUser::new(mux_mac));
     mux_mac.add_user(radio_mac);
 
+    let radio_virtual_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm));
     let radio_driver = static_init!(
-        capsules::ieee802154::RadioDriver<'static>,
+        capsules::ieee802154::RadioDriver<'static,
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
         capsules::ieee802154::RadioDriver::new(radio_mac,
+                                               radio_virtual_alarm,
                                                kernel::Grant::create(),
                                                &mut RADIO_BUF));
+    radio_driver.set_rx_hold_buffers(&mut RADIO_RX_HOLD_BUF);
+    radio_driver.set_aggregation_buffers(&mut RADIO_AGGREGATE_BUF);
+    radio_driver.set_tx_queue_buffers(&mut RADIO_TX_QUEUE_BUF);
+    radio_driver.set_pattern_buffers(&mut RADIO_PATTERN_BUF);
 
     rf233_mac.set_key_procedure(radio_driver);
     rf233_mac.set_device_procedure(radio_driver);
     radio_mac.set_transmit_client(radio_driver);
     radio_mac.set_receive_client(radio_driver);
+    radio_mac.set_measurement_client(radio_driver);
+    radio_mac.set_power_client(radio_driver);
+    radio_mac.set_config_client(radio_driver);
+    radio_virtual_alarm.set_client(radio_driver);
     radio_mac.set_pan(0xABCD);
     radio_mac.set_address(0x1008);
 
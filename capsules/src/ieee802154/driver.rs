@@ -5,16 +5,114 @@
 //! known link neighbors, which is needed for 802.15.4 security.
 
 use core::cell::Cell;
-use core::cmp::min;
+use core::cmp::{max, min};
 use ieee802154::mac;
 use kernel::{AppId, Driver, Callback, AppSlice, Shared, Grant, ReturnCode};
 use kernel::common::take_cell::{MapCell, TakeCell};
+use kernel::hil::radio;
+use kernel::hil::time;
+use kernel::hil::time::Frequency;
 
-use net::ieee802154::{MacAddress, PanID, Header, SecurityLevel, KeyId, AddressMode};
+use net::ieee802154::{MacAddress, PanID, Header, SecurityLevel, KeyId, AddressMode,
+                      FrameVersion, HeaderIE, PayloadIE, MAX_HEADER_IES, MAX_PAYLOAD_IES};
 use net::stream::{decode_u8, decode_bytes, encode_u8, encode_bytes, SResult};
 
 const MAX_NEIGHBORS: usize = 4;
 const MAX_KEYS: usize = 4;
+const MAX_NOISE_WINDOW: usize = 16;
+
+/// First payload byte of an aggregated frame. This lies in the 6LoWPAN NALP
+/// (not a LoWPAN frame) dispatch range, so it cannot be mistaken for a 6LoWPAN
+/// frame.
+const AGGREGATE_DISPATCH: u8 = 0x3A;
+/// Largest aggregated payload, including the dispatch byte. This assumes the
+/// smallest possible MAC header, so secured aggregated frames might not fit.
+const MAX_AGGREGATE_LEN: usize = radio::MAX_FRAME_SIZE - radio::MIN_MHR_SIZE - radio::MFR_SIZE;
+/// Size of each buffer the board provides to aggregate sub-frames in.
+pub const AGGREGATE_BUF_LEN: usize = MAX_AGGREGATE_LEN;
+/// Default time after which a partially filled aggregated frame is sent.
+const DEFAULT_AGGREGATE_TIMEOUT_MS: u32 = 100;
+/// Longest payload prefix that received frames can be matched against.
+const MAX_PAYLOAD_PATTERN: usize = 16;
+/// Size of each buffer the board provides to hold a payload pattern and its
+/// mask in.
+pub const PATTERN_BUF_LEN: usize = 2 * MAX_PAYLOAD_PATTERN;
+
+/// Number of buckets the duty cycle window is divided into. Airtime leaves
+/// the window one bucket at a time.
+const DUTY_CYCLE_BUCKETS: usize = 10;
+/// Default duty cycle window, one hour as used by most regulations.
+const DEFAULT_DUTY_CYCLE_WINDOW_MS: u32 = 60 * 60 * 1000;
+/// Time on air of one octet at the 2.4 GHz O-QPSK data rate of 250 kb/s.
+const OCTET_AIRTIME_US: u32 = 32;
+/// Octets sent on air in addition to the frame: preamble, SFD and PHR.
+const PHY_OVERHEAD_OCTETS: usize = 6;
+
+/// Length of the build-time limits reported by command 52.
+const LIMITS_LEN: usize = 11;
+/// Number of distinct source addresses tracked while counting frames.
+const MAX_COUNTED_SOURCES: usize = 8;
+/// Number of recently received (source, sequence number) pairs remembered to
+/// suppress duplicate frames.
+const MAX_SEEN_FRAMES: usize = 8;
+/// Length of the statistics reported by command 93.
+const STATS_LEN: usize = 24;
+/// Length of the configuration snapshot reported by command 108.
+const CONFIG_LEN: usize = 16;
+/// Bits reported by the configuration callback for each parameter changed
+/// since the previous commit.
+const CONFIG_SHORT_ADDRESS: usize = 1 << 0;
+const CONFIG_LONG_ADDRESS: usize = 1 << 1;
+const CONFIG_PAN: usize = 1 << 2;
+const CONFIG_CHANNEL: usize = 1 << 3;
+const CONFIG_TX_POWER: usize = 1 << 4;
+/// Supply voltage assumed when estimating transmit energy, in millivolts.
+const SUPPLY_MV: u32 = 3000;
+/// Number of bins in the histogram of received signal strengths.
+const RSSI_BINS: usize = 8;
+/// Lower bound of the second histogram bin, in dBm. The first bin holds
+/// everything below it.
+const RSSI_BIN_BASE: i16 = -86;
+/// Width of each histogram bin, in dB.
+const RSSI_BIN_WIDTH: i16 = 8;
+/// Largest payload of an unsecured frame with the smallest possible header.
+const MAX_PAYLOAD_LEN: usize = radio::MAX_FRAME_SIZE - radio::MIN_MHR_SIZE - radio::MFR_SIZE;
+/// Default average signal strength, in dBm, below which the link is marginal.
+const DEFAULT_MARGINAL_RSSI: i8 = -85;
+/// Default number of consecutive unacknowledged transmissions after which the
+/// link is marginal.
+const DEFAULT_MARGINAL_FAILURES: u32 = 3;
+/// Number of transmissions each app can queue behind its pending one.
+const TX_QUEUE_LEN: usize = 4;
+/// Size of each buffer the board provides to queue transmissions in.
+pub const TX_QUEUE_BUF_LEN: usize = MAX_PAYLOAD_LEN;
+/// Flag in `arg1` of commands 26 and 79 to send to the long address in the
+/// config buffer, the same way as command 79.
+const TX_LONG_ADDRESS: usize = 1 << 24;
+/// Flag in `arg1` of commands 26 and 79 to send the frame without requesting
+/// an acknowledgement.
+const TX_NO_ACK: usize = 1 << 25;
+/// Number of received frames that can be held for an app until it fetches
+/// them.
+const RX_HOLD_SLOTS: usize = 2;
+/// Size of each buffer the board provides to hold received frames in.
+pub const RX_HOLD_BUF_LEN: usize = radio::MAX_BUF_SIZE;
+/// Largest number of buffers the board can provide for each feature that
+/// borrows them.
+const MAX_POOL_BUFS: usize = 8;
+/// Number of radio events kept in the trace ring.
+const TRACE_LEN: usize = 16;
+/// Size of one trace ring entry as copied out by command 107.
+const TRACE_ENTRY_LEN: usize = 8;
+/// Default signal strength, in dBm, at or above which a clear channel
+/// assessment finds the channel busy. This matches the energy detection
+/// threshold the radio uses for CSMA-CA by default.
+const DEFAULT_CCA_THRESHOLD: i8 = -77;
+/// Payload of the frame sent by the loopback self-test.
+const LOOPBACK_PAYLOAD: [u8; 8] = [0x4c, 0x4f, 0x4f, 0x50, 0x00, 0xff, 0x55, 0xaa];
+/// Time to wait for the loopback self-test frame to be received, in
+/// milliseconds, after it was transmitted.
+const LOOPBACK_TIMEOUT_MS: u32 = 100;
 
 /// Syscall number
 pub const DRIVER_NUM: usize = 0x30001;
@@ -143,13 +241,542 @@ impl KeyDescriptor {
     }
 }
 
+/// Decodes a list of information elements in the standard format: any header
+/// IEs, followed by any payload IEs, without list terminators. Returns the
+/// number of header and payload IEs decoded into the given arrays.
+fn decode_ies<'b>(buf: &'b [u8],
+                  header_ies: &mut [HeaderIE<'b>; MAX_HEADER_IES],
+                  payload_ies: &mut [PayloadIE<'b>; MAX_PAYLOAD_IES])
+                  -> Option<(usize, usize)> {
+    let (mut off, mut num_header_ies, mut num_payload_ies) = (0, 0, 0);
+    while off < buf.len() {
+        // The type bit is the most significant bit of the big-endian IE
+        // control field
+        if buf[off] & 0x80 == 0 {
+            // Header IEs cannot follow payload IEs
+            if num_payload_ies != 0 || num_header_ies == MAX_HEADER_IES {
+                return None;
+            }
+            let (next_off, ie) = match HeaderIE::decode(&buf[off..]).done() {
+                Some(result) => result,
+                None => {
+                    return None;
+                }
+            };
+            if ie.is_termination() {
+                return None;
+            }
+            header_ies[num_header_ies] = ie;
+            num_header_ies += 1;
+            off += next_off;
+        } else {
+            if num_payload_ies == MAX_PAYLOAD_IES {
+                return None;
+            }
+            let (next_off, ie) = match PayloadIE::decode(&buf[off..]).done() {
+                Some(result) => result,
+                None => {
+                    return None;
+                }
+            };
+            if ie.is_termination() {
+                return None;
+            }
+            payload_ies[num_payload_ies] = ie;
+            num_payload_ies += 1;
+            off += next_off;
+        }
+    }
+    Some((num_header_ies, num_payload_ies))
+}
+
+/// Encodes the information elements of a received header in the format
+/// accepted by `decode_ies`, preceded by one byte holding the encoded length.
+/// Elements that do not fit in the buffer are left out.
+fn encode_ies(header: &Header, buf: &mut [u8]) {
+    if buf.len() == 0 {
+        return;
+    }
+    let mut off = 1;
+    for ie in header.header_ies[..header.header_ies_len].iter() {
+        match ie.encode(&mut buf[off..]).done() {
+            Some((ie_len, _)) => off += ie_len,
+            None => break,
+        }
+    }
+    for ie in header.payload_ies[..header.payload_ies_len].iter() {
+        match ie.encode(&mut buf[off..]).done() {
+            Some((ie_len, _)) => off += ie_len,
+            None => break,
+        }
+    }
+    buf[0] = min(off - 1, 0xff) as u8;
+}
+
+/// Length of the metadata written into the receive metadata buffer.
+const RX_METADATA_LEN: usize = 16;
+/// Length of the receive metadata including the receive timestamp.
+const RX_METADATA_TIMESTAMP_LEN: usize = RX_METADATA_LEN + 4;
+
+/// Encodes the frame type, sequence number, source address and PAN IDs of a
+/// received frame, followed by the time it was received at if there is room,
+/// into `buf`, in the format described for the receive metadata buffer.
+/// Buffers that are too short are left untouched.
+fn encode_rx_metadata(header: &Header, timestamp: u32, buf: &mut [u8]) {
+    if buf.len() < RX_METADATA_LEN {
+        return;
+    }
+    if buf.len() >= RX_METADATA_TIMESTAMP_LEN {
+        for i in 0..4 {
+            buf[RX_METADATA_LEN + i] = (timestamp >> (8 * i)) as u8;
+        }
+    }
+    buf[0] = header.frame_type as u8;
+    buf[1] = header.seq.is_some() as u8;
+    buf[2] = header.seq.unwrap_or(0);
+    buf[3] = AddressMode::from(&header.src_addr) as u8;
+    let mut src_addr = [0; 8];
+    match header.src_addr {
+        Some(MacAddress::Short(addr)) => {
+            src_addr[0] = addr as u8;
+            src_addr[1] = (addr >> 8) as u8;
+        }
+        Some(MacAddress::Long(addr)) => src_addr = addr,
+        None => {}
+    }
+    buf[4..12].copy_from_slice(&src_addr);
+    let dst_pan = header.dst_pan.unwrap_or(0);
+    let src_pan = header.src_pan.unwrap_or(0);
+    buf[12] = dst_pan as u8;
+    buf[13] = (dst_pan >> 8) as u8;
+    buf[14] = src_pan as u8;
+    buf[15] = (src_pan >> 8) as u8;
+}
+
+/// Length of the status written into the transmit status buffer.
+const TX_STATUS_LEN: usize = 8;
+
+/// Encodes the outcome of a transmission into `buf`, in the format described
+/// for the transmit status buffer. Buffers that are too short are left
+/// untouched.
+fn encode_tx_status(result: ReturnCode,
+                    ack_flags: usize,
+                    retries: usize,
+                    error: Option<TxError>,
+                    timestamp: u32,
+                    buf: &mut [u8]) {
+    if buf.len() < TX_STATUS_LEN {
+        return;
+    }
+    buf[0] = isize::from(result) as u8;
+    buf[1] = ack_flags as u8;
+    buf[2] = retries as u8;
+    buf[3] = error.map_or(0, |err| err as u8);
+    for i in 0..4 {
+        buf[4 + i] = (timestamp >> (8 * i)) as u8;
+    }
+}
+
+/// The timed operations that share the driver's alarm. Each one has its own
+/// deadline, and the alarm is always set for the earliest of them.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Timer {
+    NoiseSample = 0,
+    AggregateFlush = 1,
+    Loopback = 2,
+}
+
+const NUM_TIMERS: usize = 3;
+const TIMERS: [Timer; NUM_TIMERS] = [Timer::NoiseSample, Timer::AggregateFlush, Timer::Loopback];
+
+/// How received frames that do not fit into an app's read buffer are handled.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum OversizedPolicy {
+    /// The frame is not delivered to the app.
+    Drop = 0,
+    /// As much of the frame as fits is delivered, flagged as truncated.
+    Truncate = 1,
+    /// Like `Truncate`, and a diagnostic is also printed to the debug output.
+    TruncateAndLog = 2,
+}
+
+impl OversizedPolicy {
+    pub fn from_usize(policy: usize) -> Option<OversizedPolicy> {
+        match policy {
+            0 => Some(OversizedPolicy::Drop),
+            1 => Some(OversizedPolicy::Truncate),
+            2 => Some(OversizedPolicy::TruncateAndLog),
+            _ => None,
+        }
+    }
+}
+
+/// Why the most recent transmission failed, in more detail than its
+/// `ReturnCode`.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum TxError {
+    /// Every clear channel assessment found the channel busy, so the frame
+    /// was never sent.
+    ChannelAccessFailure = 1,
+    /// The frame was sent, but no acknowledgement arrived after all retries.
+    NoAck = 2,
+    /// The radio failed while sending the frame, such as a frame buffer
+    /// underrun.
+    Hardware = 3,
+    /// The transmission failed for another reason, such as the radio being
+    /// turned off.
+    Other = 4,
+}
+
+impl TxError {
+    /// Classifies the outcome of a transmission, returning `None` if it
+    /// succeeded.
+    fn from_result(result: ReturnCode,
+                   acked: bool,
+                   no_ack: bool,
+                   cca_clear: Option<bool>)
+                   -> Option<TxError> {
+        match result {
+            ReturnCode::SUCCESS if acked || no_ack => None,
+            // The radio reports a channel access failure as an unacknowledged
+            // frame, so the last CCA tells the two apart
+            ReturnCode::SUCCESS if cca_clear == Some(false) => Some(TxError::ChannelAccessFailure),
+            ReturnCode::SUCCESS | ReturnCode::ENOACK => Some(TxError::NoAck),
+            ReturnCode::FAIL => Some(TxError::Hardware),
+            _ => Some(TxError::Other),
+        }
+    }
+}
+
+/// Radio events recorded in the trace ring for post-mortem debugging. Each
+/// entry also carries an 8-bit detail and a 16-bit argument.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum TraceEvent {
+    /// An app issued a command. The argument is the command number.
+    Command = 1,
+    /// A transmission was handed to the radio. The detail is the index of the
+    /// transmitting app.
+    TxStarted = 2,
+    /// A transmission completed. The detail is its result and the argument
+    /// whether it was acknowledged.
+    TxDone = 3,
+    /// A frame was received. The detail is whether its FCS was valid and the
+    /// argument the length of its payload.
+    Received = 4,
+    /// A received frame was dropped. The detail is the reason, one of the
+    /// `TRACE_DROP_*` constants.
+    Dropped = 5,
+}
+
+/// The frame arrived within the receive gap after a transmission.
+const TRACE_DROP_RX_GAP: u8 = 1;
+/// The frame was a duplicate of a recently received one.
+const TRACE_DROP_DUPLICATE: u8 = 2;
+/// The app had no read buffer allowed.
+const TRACE_DROP_NO_BUFFER: u8 = 3;
+/// Every slot for held frames was taken.
+const TRACE_DROP_HOLD_FULL: u8 = 4;
+/// The frame arrived too soon after the app's previous rx callback.
+const TRACE_DROP_RATE_LIMIT: u8 = 5;
+
+/// Bits of the argument to command 112 holding the minimum interval between
+/// rx callbacks, in milliseconds.
+const RX_INTERVAL_MASK: usize = 0xffffff;
+/// Bit in the argument to command 112 that holds frames arriving within the
+/// interval instead of dropping them.
+const RX_INTERVAL_HOLD: usize = 1 << 24;
+
+/// A transmission queued behind an app's pending one. Its payload is copied
+/// into the buffer of `tx_queue_pool` with the same index, so that the app can
+/// reuse its write buffer right away.
+#[derive(Copy, Clone)]
+struct QueuedTx {
+    tx: (MacAddress, Option<(SecurityLevel, KeyId)>),
+    payload_len: usize,
+    /// Whether the frame is sent without requesting an acknowledgement.
+    no_ack: bool,
+}
+
+/// Counters that are always kept, to help debug lost frames. They wrap
+/// around on overflow.
+struct RadioStats {
+    /// Frames handed to the radio for transmission.
+    tx_frames: Cell<u32>,
+    /// Transmissions that succeeded and were acknowledged, if unicast.
+    tx_success: Cell<u32>,
+    /// Transmissions that failed, could not be started, or were not
+    /// acknowledged.
+    tx_failures: Cell<u32>,
+    /// Frames received with a valid FCS.
+    rx_frames: Cell<u32>,
+    /// Received frames not delivered to an app that had no read buffer.
+    rx_no_buffer: Cell<u32>,
+}
+
+impl RadioStats {
+    fn new() -> RadioStats {
+        RadioStats {
+            tx_frames: Cell::new(0),
+            tx_success: Cell::new(0),
+            tx_failures: Cell::new(0),
+            rx_frames: Cell::new(0),
+            rx_no_buffer: Cell::new(0),
+        }
+    }
+
+    fn increment(counter: &Cell<u32>) {
+        counter.set(counter.get().wrapping_add(1));
+    }
+}
+
+/// Buffers of equal size that the board provides for a feature, and that are
+/// lent to apps while they use it. This keeps them out of the grant of every
+/// app using the driver, which only records the indices of the buffers it
+/// holds.
+struct BufferPool {
+    /// The buffers, back to back.
+    bufs: TakeCell<'static, [u8]>,
+    /// Size of each buffer.
+    buf_len: usize,
+    /// Number of buffers in `bufs`.
+    count: Cell<usize>,
+    /// The app each buffer is lent to, if any.
+    owners: Cell<[Option<AppId>; MAX_POOL_BUFS]>,
+}
+
+impl BufferPool {
+    fn new(buf_len: usize) -> BufferPool {
+        BufferPool {
+            bufs: TakeCell::empty(),
+            buf_len: buf_len,
+            count: Cell::new(0),
+            owners: Cell::new([None; MAX_POOL_BUFS]),
+        }
+    }
+
+    /// Provides the buffers, while the board starts up. Bytes beyond the last
+    /// whole buffer, or beyond `MAX_POOL_BUFS` buffers, are left unused.
+    fn set_buffers(&self, bufs: &'static mut [u8]) {
+        self.count.set(min(bufs.len() / self.buf_len, MAX_POOL_BUFS));
+        self.bufs.replace(bufs);
+    }
+
+    /// Lends a free buffer to `appid`, returning its index, or `None` if
+    /// every buffer is taken.
+    fn claim(&self, appid: AppId) -> Option<usize> {
+        let mut owners = self.owners.get();
+        let free = owners[..self.count.get()].iter().position(|owner| owner.is_none());
+        free.map(|index| {
+            owners[index] = Some(appid);
+            self.owners.set(owners);
+            index
+        })
+    }
+
+    /// Returns the buffer at `index` to the pool.
+    fn release(&self, index: usize) {
+        let mut owners = self.owners.get();
+        owners[index] = None;
+        self.owners.set(owners);
+    }
+
+    /// Returns every buffer lent to `appid` to the pool.
+    fn release_all(&self, appid: AppId) {
+        let mut owners = self.owners.get();
+        for owner in owners.iter_mut() {
+            if *owner == Some(appid) {
+                *owner = None;
+            }
+        }
+        self.owners.set(owners);
+    }
+
+    /// Passes the buffer at `index` to `closure`.
+    fn map<F, R>(&self, index: usize, closure: F) -> Option<R>
+        where F: FnOnce(&mut [u8]) -> R
+    {
+        let buf_len = self.buf_len;
+        self.bufs.map(|bufs| closure(&mut bufs[index * buf_len..(index + 1) * buf_len]))
+    }
+}
+
 pub struct App {
     rx_callback: Option<Callback>,
     tx_callback: Option<Callback>,
+    channel_callback: Option<Callback>,
+    cca_callback: Option<Callback>,
+    loopback_callback: Option<Callback>,
+    ready_callback: Option<Callback>,
+    scan_callback: Option<Callback>,
+    config_callback: Option<Callback>,
     app_read: Option<AppSlice<Shared, u8>>,
     app_write: Option<AppSlice<Shared, u8>>,
     app_cfg: Option<AppSlice<Shared, u8>>,
-    pending_tx: Option<(u16, Option<(SecurityLevel, KeyId)>)>,
+    /// Information elements inserted into every transmitted frame.
+    app_tx_ies: Option<AppSlice<Shared, u8>>,
+    /// Information elements of the most recently received frame.
+    app_rx_ies: Option<AppSlice<Shared, u8>>,
+    /// Header fields of the most recently received frame.
+    app_rx_meta: Option<AppSlice<Shared, u8>>,
+    /// Outcome of the most recent transmission.
+    app_tx_status: Option<AppSlice<Shared, u8>>,
+    pending_tx: Option<(MacAddress, Option<(SecurityLevel, KeyId)>)>,
+    /// Whether `pending_tx` is sent without requesting an acknowledgement.
+    pending_no_ack: bool,
+    /// Whether received frames are preceded by a length byte in `app_read`.
+    rx_length_prefix: bool,
+    /// If set, only frames from this short source address are delivered.
+    src_filter: Option<u16>,
+    /// If set, only frames whose sequence number lies in this inclusive
+    /// range, which may wrap around, are delivered.
+    seq_range: Option<(u8, u8)>,
+    /// If set, the index of the buffer in `pattern_pool` holding the payload
+    /// pattern and its mask, and the length of the pattern. Only frames whose
+    /// payload begins with the pattern, compared under the mask, are
+    /// delivered.
+    pattern: Option<(usize, usize)>,
+    /// Whether the rx callback reports if frames were addressed to this node.
+    check_dst: bool,
+    /// Whether frames with an invalid FCS are delivered, flagged as such.
+    rx_crc_errors: bool,
+    /// Whether received frames are withheld from the app, which keeps its
+    /// buffers and callbacks registered.
+    rx_paused: bool,
+    /// How frames that do not fit into `app_read` are handled.
+    oversized: OversizedPolicy,
+    /// Priority of this app's transmissions. Pending transmissions of apps
+    /// with higher priorities are sent first.
+    priority: u8,
+    /// Whether `app_read` and `app_write` stay allowed across operations.
+    persistent_buffers: bool,
+    /// Length of the payload in `app_write` to send with the next
+    /// transmission, when the buffers are persistent.
+    tx_len: Option<usize>,
+    /// Number of bytes at the start of `app_write` that precede the payload
+    /// and are not transmitted.
+    tx_offset: usize,
+
+    /// Whether transmitted payloads are aggregated into shared frames.
+    aggregate: bool,
+    /// Index of the buffer in `agg_pool` holding the sub-frames waiting to be
+    /// sent in one aggregated frame, starting with the aggregation dispatch
+    /// byte, while there are any.
+    agg_buf: Option<usize>,
+    agg_len: usize,
+    /// Destination and security shared by the buffered sub-frames.
+    agg_tx: Option<(MacAddress, Option<(SecurityLevel, KeyId)>)>,
+    /// Time at which the first buffered sub-frame was added.
+    agg_started: u32,
+    /// Whether `pending_tx` sends `agg_buf` instead of `app_write`.
+    pending_aggregate: bool,
+    /// Index of the buffer in `agg_pool` holding the received sub-frames that
+    /// have not been delivered yet, while there are any, and the rx callback
+    /// arguments of the frame they arrived in.
+    rx_subframes: Option<usize>,
+    rx_subframes_len: usize,
+    rx_subframes_off: usize,
+    rx_subframes_args: (usize, usize, usize),
+
+    /// Indices in `tx_queue_pool` of the transmissions queued behind
+    /// `pending_tx`, oldest first, in a ring starting at `tx_queue_head`.
+    tx_queue: [usize; TX_QUEUE_LEN],
+    tx_queue_head: usize,
+    tx_queue_len: usize,
+    /// Index in `tx_queue_pool` and length of the payload of the queued
+    /// transmission that was moved into `pending_tx`, if any, which is sent
+    /// instead of `app_write`.
+    pending_queued: Option<(usize, usize)>,
+    /// Whether `pending_tx` is sent without a payload instead of `app_write`.
+    pending_empty: bool,
+
+    /// Whether received frames are held in `rx_held` until the app fetches
+    /// them, instead of being delivered to the rx callback.
+    rx_hold: bool,
+    /// Held frames, oldest first, in a ring starting at `rx_held_head`: the
+    /// index of the buffer in `rx_hold_pool` holding each, and the offset and
+    /// length of its payload.
+    rx_held: [usize; RX_HOLD_SLOTS],
+    rx_held_payloads: [(usize, usize); RX_HOLD_SLOTS],
+    rx_held_head: usize,
+    rx_held_len: usize,
+
+    /// Minimum time between two rx callbacks in milliseconds, or 0 to invoke
+    /// the rx callback for every frame.
+    rx_interval: u32,
+    /// Whether frames arriving within `rx_interval` are held in `rx_held`
+    /// rather than dropped.
+    rx_interval_hold: bool,
+    /// When the rx callback was last invoked while rate limited.
+    rx_last_callback: Option<u32>,
+    /// Frames suppressed by the rate limit since the last rx callback.
+    rx_suppressed: usize,
+
+    /// Signal strength and link quality indication of the last frame copied
+    /// into `app_read`, or `None` if there has not been one yet.
+    rx_quality: Option<(i8, u8)>,
+}
+
+impl App {
+    /// Length of the payload the next transmission would send, if there is
+    /// one.
+    fn tx_payload_len(&self) -> Option<usize> {
+        let offset = self.tx_offset;
+        if self.persistent_buffers {
+            self.tx_len.and_then(|len| {
+                self.app_write
+                    .as_ref()
+                    .and_then(|payload| if offset + len <= payload.len() {
+                        Some(len)
+                    } else {
+                        None
+                    })
+            })
+        } else {
+            self.app_write
+                .as_ref()
+                .and_then(|payload| payload.len().checked_sub(offset))
+        }
+    }
+
+    /// Consumes the payload for the next transmission and passes it to
+    /// `closure`. In the default mode, this gives up the write buffer. With
+    /// persistent buffers, the buffer stays allowed, but its payload length
+    /// has to be updated again before the next transmission, so that data the
+    /// app has not marked as ready is never sent. Either way, the payload
+    /// starts `tx_offset` bytes into the buffer.
+    fn with_tx_payload<F, R>(&mut self, closure: F) -> Option<R>
+        where F: FnOnce(&[u8]) -> R
+    {
+        let offset = self.tx_offset;
+        if self.persistent_buffers {
+            let len = match self.tx_len.take() {
+                Some(len) => len,
+                None => {
+                    return None;
+                }
+            };
+            self.app_write.as_ref().and_then(|payload| if offset + len <= payload.len() {
+                Some(closure(&payload.as_ref()[offset..offset + len]))
+            } else {
+                None
+            })
+        } else {
+            self.app_write.take().as_ref().and_then(|payload| if offset <= payload.len() {
+                Some(closure(&payload.as_ref()[offset..]))
+            } else {
+                None
+            })
+        }
+    }
+
+    /// Bits reporting the frames suppressed by the rate limit since the last
+    /// rx callback, to be set in the encoded destination address of the next
+    /// one. Resets the count.
+    fn take_suppressed(&mut self) -> usize {
+        let count = min(self.rx_suppressed, MAX_SUPPRESSED);
+        self.rx_suppressed = 0;
+        count << SUPPRESSED_SHIFT
+    }
 }
 
 impl Default for App {
@@ -157,17 +784,67 @@ impl Default for App {
         App {
             rx_callback: None,
             tx_callback: None,
+            channel_callback: None,
+            cca_callback: None,
+            loopback_callback: None,
+            ready_callback: None,
+            scan_callback: None,
+            config_callback: None,
             app_read: None,
             app_write: None,
             app_cfg: None,
+            app_tx_ies: None,
+            app_rx_ies: None,
+            app_rx_meta: None,
+            app_tx_status: None,
             pending_tx: None,
+            pending_no_ack: false,
+            rx_length_prefix: false,
+            src_filter: None,
+            seq_range: None,
+            pattern: None,
+            check_dst: false,
+            rx_crc_errors: false,
+            rx_paused: false,
+            oversized: OversizedPolicy::Truncate,
+            priority: 0,
+            persistent_buffers: false,
+            tx_len: None,
+            tx_offset: 0,
+            aggregate: false,
+            agg_buf: None,
+            agg_len: 0,
+            agg_tx: None,
+            agg_started: 0,
+            pending_aggregate: false,
+            rx_subframes: None,
+            rx_subframes_len: 0,
+            rx_subframes_off: 0,
+            rx_subframes_args: (0, 0, 0),
+            tx_queue: [0; TX_QUEUE_LEN],
+            tx_queue_head: 0,
+            tx_queue_len: 0,
+            pending_queued: None,
+            pending_empty: false,
+            rx_hold: false,
+            rx_held: [0; RX_HOLD_SLOTS],
+            rx_held_payloads: [(0, 0); RX_HOLD_SLOTS],
+            rx_held_head: 0,
+            rx_held_len: 0,
+            rx_interval: 0,
+            rx_interval_hold: false,
+            rx_last_callback: None,
+            rx_suppressed: 0,
+            rx_quality: None,
         }
     }
 }
 
-pub struct RadioDriver<'a> {
+pub struct RadioDriver<'a, A: time::Alarm + 'a> {
     /// Underlying MAC device, possibly multiplexed
     mac: &'a mac::Mac<'a>,
+    /// Alarm used to timestamp and time out radio operations.
+    alarm: &'a A,
 
     /// List of (short address, long address) pairs representing IEEE 802.15.4
     /// neighbors.
@@ -185,28 +862,306 @@ pub struct RadioDriver<'a> {
     apps: Grant<App>,
     /// ID of app whose transmission request is being processed.
     current_app: Cell<Option<AppId>>,
+    /// Index of the app whose transmission was started most recently, for
+    /// round-robin scheduling among apps of equal priority.
+    last_tx_idx: Cell<usize>,
+    /// ID of the app that receives frames not accepted by any other app.
+    fallback_app: Cell<Option<AppId>>,
+    /// Time at which the transmission of `current_app` was started.
+    tx_started: Cell<u32>,
+    /// Deadlines, in alarm tics, of the timed operations sharing `alarm`.
+    timers: Cell<[Option<u32>; NUM_TIMERS]>,
+
+    /// Interval between noise floor samples in milliseconds, or 0 if
+    /// sampling is disabled.
+    noise_interval: Cell<u32>,
+    /// Number of most recent samples averaged into the noise floor estimate.
+    noise_window: Cell<usize>,
+    /// Ring of the most recent noise floor samples, in dBm.
+    noise_samples: MapCell<[i8; MAX_NOISE_WINDOW]>,
+    /// Index in `noise_samples` that the next sample is written to.
+    noise_next: Cell<usize>,
+    /// Number of valid samples in `noise_samples`.
+    noise_count: Cell<usize>,
+
+    /// Time after which a partially filled aggregated frame is sent, in
+    /// milliseconds.
+    agg_timeout: Cell<u32>,
+
+    /// Permitted percentage of time spent transmitting, or 0 if the duty
+    /// cycle is not enforced.
+    duty_cycle: Cell<u32>,
+    /// Length of the rolling window the duty cycle applies to, in
+    /// milliseconds.
+    duty_cycle_window: Cell<u32>,
+    /// Airtime in microseconds spent in each bucket of the window.
+    airtime_buckets: Cell<[u32; DUTY_CYCLE_BUCKETS]>,
+    /// Index of the bucket that airtime is currently added to.
+    airtime_bucket: Cell<usize>,
+    /// Time at which the current bucket started.
+    airtime_bucket_start: Cell<u32>,
+    /// Remaining transmit energy budget in microjoules, or `None` if
+    /// transmissions are not limited by energy.
+    energy_budget: Cell<Option<u32>>,
+
+    /// Minimum time after a transmission completes before received frames
+    /// are delivered again, in microseconds.
+    rx_gap: Cell<u32>,
+    /// Time at which the last transmission completed.
+    tx_done_at: Cell<u32>,
+    /// Time at which the radio's last clear channel assessment was observed.
+    cca_at: Cell<u32>,
+    /// Time at which the frame being delivered was received.
+    rx_timestamp: Cell<u32>,
+    /// App waiting for the result of the clear channel assessment it started.
+    cca_app: Cell<Option<AppId>>,
+    /// Signal strength at or above which the channel is busy.
+    cca_threshold: Cell<i8>,
+    /// App waiting for the result of the energy detection scan it started.
+    scan_app: Cell<Option<AppId>>,
+    /// Channel being measured by the energy detection scan.
+    scan_channel: Cell<u8>,
+    /// Channel to return to once the energy detection scan is done.
+    scan_saved_channel: Cell<u8>,
+    /// Signal strength measured on each channel by the last scan, in dBm.
+    scan_results: Cell<[i8; mac::NUM_CHANNELS]>,
+    /// The `CONFIG_*` bits of the parameters changed since the last commit.
+    config_changed: Cell<usize>,
+    /// Whether an app committed the configuration with command 7, as opposed
+    /// to the driver itself, and has not been notified yet.
+    config_requested: Cell<bool>,
+    /// App waiting for the result of the loopback self-test it started.
+    loopback_app: Cell<Option<AppId>>,
+    /// Whether the loopback self-test frame is being transmitted.
+    loopback_tx: Cell<bool>,
+
+    /// Counters of transmitted and received frames.
+    stats: RadioStats,
+
+    /// Whether received frames already in `seen_frames` are dropped.
+    suppress_duplicates: Cell<bool>,
+    /// Source addresses and sequence numbers of recently received frames.
+    seen_frames: Cell<[Option<(MacAddress, u8)>; MAX_SEEN_FRAMES]>,
+    /// Index of the oldest entry of `seen_frames`, replaced next.
+    seen_next: Cell<usize>,
+
+    /// Whether radio events are recorded in `trace`.
+    tracing: Cell<bool>,
+    /// The most recent radio events with the time in milliseconds at which
+    /// they occurred.
+    trace: Cell<[Option<(u32, TraceEvent, u8, u16)>; TRACE_LEN]>,
+    /// Index of the oldest entry of `trace`, replaced next.
+    trace_next: Cell<usize>,
+
+    /// Whether received frames are only counted instead of being delivered.
+    count_only: Cell<bool>,
+    /// Number of frames received since the counters were last reset.
+    counted_frames: Cell<u32>,
+    /// Sum of the lengths of the counted frames, excluding the FCS.
+    counted_octets: Cell<u32>,
+    /// Distinct source addresses of the counted frames.
+    counted_sources: Cell<[Option<MacAddress>; MAX_COUNTED_SOURCES]>,
+    /// Number of times a received frame did not fit into an app's read
+    /// buffer.
+    oversized_frames: Cell<u32>,
+    /// Number of received frames whose signal strength fell into each bin.
+    rssi_histogram: Cell<[u16; RSSI_BINS]>,
+
+    /// Moving average of the signal strength of received frames, in dBm, or
+    /// `None` if no frame has been received yet.
+    link_rssi: Cell<Option<i16>>,
+    /// Number of consecutive transmissions that were not acknowledged.
+    link_failures: Cell<u32>,
+    /// Whether the transmission in flight did not request an acknowledgement,
+    /// like every broadcast.
+    tx_no_ack: Cell<bool>,
+    /// Why the most recent transmission failed, or `None` if it succeeded or
+    /// is still in flight.
+    last_tx_error: Cell<Option<TxError>>,
+    /// Whether transmitted frames carry the long source address instead of
+    /// the short one.
+    src_addr_long: Cell<bool>,
+    /// Largest payload permitted while the link is marginal, or 0 if payload
+    /// sizes are not adapted to the link quality.
+    marginal_payload_len: Cell<usize>,
+    /// Average signal strength below which the link is marginal.
+    marginal_rssi: Cell<i8>,
+    /// Number of consecutive unacknowledged transmissions from which on the
+    /// link is marginal.
+    marginal_failures: Cell<u32>,
+
+    /// Buffers that received frames are held in until apps fetch them.
+    rx_hold_pool: BufferPool,
+    /// Buffers that sub-frames are aggregated in, before they are sent or
+    /// after they are received.
+    agg_pool: BufferPool,
+    /// Buffers holding the payloads of queued transmissions, and the rest of
+    /// each queued transmission.
+    tx_queue_pool: BufferPool,
+    tx_queued: MapCell<[Option<QueuedTx>; MAX_POOL_BUFS]>,
+    /// Buffers holding the payload patterns of apps (command 40).
+    pattern_pool: BufferPool,
 
     /// Buffer that stores the IEEE 802.15.4 frame to be transmitted.
     kernel_tx: TakeCell<'static, [u8]>,
 }
 
-impl<'a> RadioDriver<'a> {
+impl<'a, A: time::Alarm + 'a> RadioDriver<'a, A> {
     pub fn new(mac: &'a mac::Mac<'a>,
+               alarm: &'a A,
                grant: Grant<App>,
                kernel_tx: &'static mut [u8])
-               -> RadioDriver<'a> {
+               -> RadioDriver<'a, A> {
         RadioDriver {
             mac: mac,
+            alarm: alarm,
             neighbors: MapCell::new(Default::default()),
             num_neighbors: Cell::new(0),
             keys: MapCell::new(Default::default()),
             num_keys: Cell::new(0),
             apps: grant,
             current_app: Cell::new(None),
+            last_tx_idx: Cell::new(usize::max_value()),
+            fallback_app: Cell::new(None),
+            tx_started: Cell::new(0),
+            timers: Cell::new([None; NUM_TIMERS]),
+            noise_interval: Cell::new(0),
+            noise_window: Cell::new(MAX_NOISE_WINDOW),
+            noise_samples: MapCell::new([0; MAX_NOISE_WINDOW]),
+            noise_next: Cell::new(0),
+            noise_count: Cell::new(0),
+            agg_timeout: Cell::new(DEFAULT_AGGREGATE_TIMEOUT_MS),
+            duty_cycle: Cell::new(0),
+            duty_cycle_window: Cell::new(DEFAULT_DUTY_CYCLE_WINDOW_MS),
+            airtime_buckets: Cell::new([0; DUTY_CYCLE_BUCKETS]),
+            airtime_bucket: Cell::new(0),
+            airtime_bucket_start: Cell::new(0),
+            energy_budget: Cell::new(None),
+            rx_gap: Cell::new(0),
+            tx_done_at: Cell::new(0),
+            cca_at: Cell::new(0),
+            rx_timestamp: Cell::new(0),
+            cca_app: Cell::new(None),
+            scan_app: Cell::new(None),
+            scan_channel: Cell::new(mac::MIN_CHANNEL),
+            scan_saved_channel: Cell::new(mac::MIN_CHANNEL),
+            scan_results: Cell::new([0; mac::NUM_CHANNELS]),
+            config_changed: Cell::new(0),
+            config_requested: Cell::new(false),
+            cca_threshold: Cell::new(DEFAULT_CCA_THRESHOLD),
+            loopback_app: Cell::new(None),
+            loopback_tx: Cell::new(false),
+            stats: RadioStats::new(),
+            suppress_duplicates: Cell::new(false),
+            seen_frames: Cell::new([None; MAX_SEEN_FRAMES]),
+            seen_next: Cell::new(0),
+            tracing: Cell::new(false),
+            trace: Cell::new([None; TRACE_LEN]),
+            trace_next: Cell::new(0),
+            count_only: Cell::new(false),
+            counted_frames: Cell::new(0),
+            counted_octets: Cell::new(0),
+            counted_sources: Cell::new([None; MAX_COUNTED_SOURCES]),
+            oversized_frames: Cell::new(0),
+            rssi_histogram: Cell::new([0; RSSI_BINS]),
+            link_rssi: Cell::new(None),
+            link_failures: Cell::new(0),
+            tx_no_ack: Cell::new(false),
+            last_tx_error: Cell::new(None),
+            src_addr_long: Cell::new(false),
+            marginal_payload_len: Cell::new(0),
+            marginal_rssi: Cell::new(DEFAULT_MARGINAL_RSSI),
+            marginal_failures: Cell::new(DEFAULT_MARGINAL_FAILURES),
+            rx_hold_pool: BufferPool::new(RX_HOLD_BUF_LEN),
+            agg_pool: BufferPool::new(AGGREGATE_BUF_LEN),
+            tx_queue_pool: BufferPool::new(TX_QUEUE_BUF_LEN),
+            tx_queued: MapCell::new([None; MAX_POOL_BUFS]),
+            pattern_pool: BufferPool::new(PATTERN_BUF_LEN),
             kernel_tx: TakeCell::new(kernel_tx),
         }
     }
 
+    /// Provides the buffers that received frames are held in for commands 101
+    /// and 112, each `RX_HOLD_BUF_LEN` bytes long and back to back in `bufs`.
+    /// They are shared by all apps. Without them, no frames are held.
+    pub fn set_rx_hold_buffers(&self, bufs: &'static mut [u8]) {
+        self.rx_hold_pool.set_buffers(bufs);
+    }
+
+    /// Provides the buffers for frame aggregation (command 38), each
+    /// `AGGREGATE_BUF_LEN` bytes long and back to back in `bufs`. An app
+    /// borrows one while it buffers sub-frames to send, and another while it
+    /// has received sub-frames left to deliver. Without them, aggregation
+    /// fails.
+    pub fn set_aggregation_buffers(&self, bufs: &'static mut [u8]) {
+        self.agg_pool.set_buffers(bufs);
+    }
+
+    /// Provides the buffers that transmissions queued behind an app's pending
+    /// one are copied into (command 26), each `TX_QUEUE_BUF_LEN` bytes long
+    /// and back to back in `bufs`. They are shared by all apps. Without them,
+    /// no transmissions are queued.
+    pub fn set_tx_queue_buffers(&self, bufs: &'static mut [u8]) {
+        self.tx_queue_pool.set_buffers(bufs);
+    }
+
+    /// Provides the buffers that payload patterns are kept in (command 40),
+    /// each `PATTERN_BUF_LEN` bytes long and back to back in `bufs`. An app
+    /// borrows one while it has a pattern set. Without them, setting a pattern
+    /// fails.
+    pub fn set_pattern_buffers(&self, bufs: &'static mut [u8]) {
+        self.pattern_pool.set_buffers(bufs);
+    }
+
+    /// Returns the buffers lent to `appid` to their pools.
+    fn release_buffers(&self, appid: AppId) {
+        self.rx_hold_pool.release_all(appid);
+        self.agg_pool.release_all(appid);
+        self.tx_queue_pool.release_all(appid);
+        self.pattern_pool.release_all(appid);
+    }
+
+    /// Checks whether a received payload matches the app's payload pattern.
+    fn matches_pattern(&self, app: &App, payload: &[u8]) -> bool {
+        let (index, len) = match app.pattern {
+            Some(pattern) => pattern,
+            None => return true,
+        };
+        if payload.len() < len {
+            return false;
+        }
+        self.pattern_pool
+            .map(index, |buf| {
+                let (pattern, mask) = buf.split_at(MAX_PAYLOAD_PATTERN);
+                payload.iter()
+                    .zip(pattern[..len].iter())
+                    .zip(mask.iter())
+                    .all(|((byte, pattern), mask)| byte & mask == pattern & mask)
+            })
+            .unwrap_or(false)
+    }
+
+    /// Checks whether a received frame passes the app's source address,
+    /// sequence number and payload pattern filters.
+    fn accepts(&self, app: &App, header: &Header, payload: &[u8]) -> bool {
+        if let Some(src_filter) = app.src_filter {
+            if header.src_addr != Some(MacAddress::Short(src_filter)) {
+                return false;
+            }
+        }
+        if let Some((min_seq, max_seq)) = app.seq_range {
+            let in_range = header.seq.map_or(false, |seq| if min_seq <= max_seq {
+                min_seq <= seq && seq <= max_seq
+            } else {
+                seq >= min_seq || seq <= max_seq
+            });
+            if !in_range {
+                return false;
+            }
+        }
+        self.matches_pattern(app, payload)
+    }
+
     // Neighbor management functions
 
     /// Add a new neighbor to the end of the list if there is still space
@@ -359,432 +1314,3116 @@ impl<'a> RadioDriver<'a> {
             .unwrap_or_else(|err| err.into())
     }
 
-    /// If the driver is currently idle and there are pending transmissions,
-    /// pick an app with a pending transmission and return its `AppId`.
-    fn get_next_tx_if_idle(&self) -> Option<AppId> {
-        if self.current_app.get().is_some() {
-            return None;
+    /// Converts a duration in alarm tics to milliseconds.
+    fn tics_to_ms(tics: u32) -> u32 {
+        (tics as u64 * 1000 / <A::Frequency>::frequency() as u64) as u32
+    }
+
+    /// Converts a duration in milliseconds to alarm tics, saturating at the
+    /// largest number of tics.
+    fn ms_to_tics(ms: u32) -> u32 {
+        let tics = ms as u64 * <A::Frequency>::frequency() as u64 / 1000;
+        min(tics, u32::max_value() as u64) as u32
+    }
+
+    /// Whether the alarm can time a duration of `ms` milliseconds. Deadlines
+    /// more than half the range of the alarm away would look like they had
+    /// already passed.
+    fn ms_in_range(ms: usize) -> bool {
+        ms as u64 * <A::Frequency>::frequency() as u64 / 1000 <= i32::max_value() as u64
+    }
+
+    /// Converts a duration in alarm tics to microseconds.
+    fn tics_to_us(tics: u32) -> u32 {
+        (tics as u64 * 1_000_000 / <A::Frequency>::frequency() as u64) as u32
+    }
+
+    /// Converts a duration in microseconds to alarm tics, rounding up.
+    fn us_to_tics(us: u32) -> u32 {
+        let freq = <A::Frequency>::frequency() as u64;
+        ((us as u64 * freq + 999_999) / 1_000_000) as u32
+    }
+
+    // Timer management functions
+
+    /// Schedules `timer` to fire `ms` milliseconds from now, replacing any
+    /// deadline it already had.
+    fn start_timer(&self, timer: Timer, ms: u32) {
+        self.start_timer_tics(timer, Self::ms_to_tics(ms));
+    }
+
+    /// Schedules `timer` to fire `tics` alarm tics from now, replacing any
+    /// deadline it already had.
+    fn start_timer_tics(&self, timer: Timer, tics: u32) {
+        let mut timers = self.timers.get();
+        timers[timer as usize] = Some(self.alarm.now().wrapping_add(tics));
+        self.timers.set(timers);
+        self.arm_alarm();
+    }
+
+    /// Cancels `timer` if it is pending.
+    fn cancel_timer(&self, timer: Timer) {
+        let mut timers = self.timers.get();
+        timers[timer as usize] = None;
+        self.timers.set(timers);
+        self.arm_alarm();
+    }
+
+    /// Sets the alarm for the earliest pending timer deadline, or disables the
+    /// alarm if no timer is pending.
+    fn arm_alarm(&self) {
+        let now = self.alarm.now();
+        let earliest = self.timers
+            .get()
+            .iter()
+            .filter_map(|deadline| *deadline)
+            .min_by_key(|deadline| deadline.wrapping_sub(now) as i32);
+        match earliest {
+            Some(deadline) => self.alarm.set_alarm(deadline),
+            None => self.alarm.disable(),
         }
-        let mut pending_app = None;
-        for app in self.apps.iter() {
-            app.enter(|app, _| if app.pending_tx.is_some() {
-                pending_app = Some(app.appid());
-            });
-            if pending_app.is_some() {
-                break;
+    }
+
+    /// Performs the operation associated with a timer whose deadline passed.
+    fn timer_fired(&self, timer: Timer) {
+        match timer {
+            Timer::NoiseSample => {
+                // Only sample the channel while the radio is idle-receiving,
+                // since our own transmissions would skew the estimate.
+                if !self.radio_in_use() && self.mac.is_on() {
+                    let _ = self.mac.read_rssi();
+                }
+                let interval = self.noise_interval.get();
+                if interval > 0 {
+                    self.start_timer(Timer::NoiseSample, interval);
+                }
+            }
+            Timer::AggregateFlush => {
+                let now = self.alarm.now();
+                let timeout = Self::ms_to_tics(self.agg_timeout.get());
+                for app in self.apps.iter() {
+                    app.enter(|app, _| if app.agg_len > 0 &&
+                                          now.wrapping_sub(app.agg_started) >= timeout {
+                        Self::flush_aggregate(app);
+                    });
+                }
+                self.do_next_tx_async();
+                self.arm_aggregate_timer();
+            }
+            Timer::Loopback => {
+                // The self-test frame never came back
+                self.finish_loopback(ReturnCode::FAIL);
             }
         }
-        pending_app
     }
 
-    /// Performs `appid`'s pending transmission asynchronously. If the
-    /// transmission is not successful, the error is returned to the app via its
-    /// `tx_callback`. Assumes that the driver is currently idle and the app has
-    /// a pending transmission.
-    #[inline]
-    fn perform_tx_async(&self, appid: AppId) {
-        let result = self.perform_tx_sync(appid);
+    // Loopback self-test functions
+
+    /// Puts the radio into loopback mode and transmits a known frame to our
+    /// own address, which must then be received within
+    /// `LOOPBACK_TIMEOUT_MS`. App transmissions are held back until the
+    /// self-test finishes.
+    fn start_loopback(&self, appid: AppId) -> ReturnCode {
+        if self.radio_in_use() {
+            return ReturnCode::EBUSY;
+        }
+        if !self.mac.is_on() {
+            return ReturnCode::EOFF;
+        }
+        let result = self.mac.set_loopback(true);
         if result != ReturnCode::SUCCESS {
-            let _ = self.apps.enter(appid, |app, _| {
-                app.tx_callback
-                    .take()
-                    .map(|mut cb| cb.schedule(result.into(), 0, 0));
+            return result;
+        }
+        let result = self.kernel_tx
+            .take()
+            .map_or(ReturnCode::ENOMEM, |kbuf| {
+                let pan = self.mac.get_pan();
+                let addr = MacAddress::Short(self.mac.get_address());
+                let prepared = self.mac.prepare_data_frame(kbuf, pan, addr, pan, addr, None);
+                let mut frame = match prepared {
+                    Ok(frame) => frame,
+                    Err(kbuf) => {
+                        self.kernel_tx.replace(kbuf);
+                        return ReturnCode::FAIL;
+                    }
+                };
+                let result = frame.append_payload(&LOOPBACK_PAYLOAD);
+                if result != ReturnCode::SUCCESS {
+                    self.kernel_tx.replace(frame.into_buf());
+                    return result;
+                }
+                let (result, mbuf) = self.mac.transmit(frame);
+                if let Some(buf) = mbuf {
+                    self.kernel_tx.replace(buf);
+                }
+                result
             });
+        if result != ReturnCode::SUCCESS {
+            let _ = self.mac.set_loopback(false);
+            return result;
         }
+        self.loopback_tx.set(true);
+        self.loopback_app.set(Some(appid));
+        ReturnCode::SUCCESS
     }
 
-    /// Performs `appid`'s pending transmission synchronously. The result is
-    /// returned immediately to the app. Assumes that the driver is currently
-    /// idle and the app has a pending transmission.
-    #[inline]
-    fn perform_tx_sync(&self, appid: AppId) -> ReturnCode {
-        self.do_with_app(appid, |app| {
-            let (dst_addr, security_needed) = match app.pending_tx.take() {
-                Some(pending_tx) => pending_tx,
-                None => {
-                    return ReturnCode::SUCCESS;
-                }
-            };
-            let result = self.kernel_tx
-                .take()
-                .map_or(ReturnCode::ENOMEM, |kbuf| {
-                    // Prepare the frame headers
-                    let pan = self.mac.get_pan();
-                    let dst_addr = MacAddress::Short(dst_addr);
-                    let src_addr = MacAddress::Short(self.mac.get_address());
-                    let mut frame = match self.mac
-                        .prepare_data_frame(kbuf, pan, dst_addr, pan, src_addr, security_needed) {
-                        Ok(frame) => frame,
-                        Err(kbuf) => {
-                            self.kernel_tx.replace(kbuf);
-                            return ReturnCode::FAIL;
-                        }
-                    };
-
-                    // Append the payload: there must be one
-                    let result = app.app_write
-                        .take()
-                        .as_ref()
-                        .map(|payload| frame.append_payload(payload.as_ref()))
-                        .unwrap_or(ReturnCode::EINVAL);
-                    if result != ReturnCode::SUCCESS {
-                        return result;
-                    }
+    /// Whether the radio is taken by a transmission, the loopback self-test
+    /// or an energy detection scan, so that app transmissions have to wait.
+    fn radio_in_use(&self) -> bool {
+        self.current_app.get().is_some() || self.loopback_app.get().is_some() ||
+        self.scan_app.get().is_some()
+    }
 
-                    // Finally, transmit the frame
-                    let (result, mbuf) = self.mac.transmit(frame);
-                    if let Some(buf) = mbuf {
-                        self.kernel_tx.replace(buf);
-                    }
-                    result
-                });
-            if result == ReturnCode::SUCCESS {
-                self.current_app.set(Some(appid));
-            }
-            result
-        })
+    /// Starts an energy detection scan, which measures the signal strength on
+    /// each channel in turn and then returns to the current channel.
+    fn start_scan(&self, appid: AppId) -> ReturnCode {
+        if self.radio_in_use() || self.cca_app.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        if !self.mac.is_on() {
+            return ReturnCode::EOFF;
+        }
+        self.scan_saved_channel.set(self.mac.get_channel());
+        self.scan_channel.set(mac::MIN_CHANNEL);
+        let result = self.measure_channel(mac::MIN_CHANNEL);
+        if result == ReturnCode::SUCCESS {
+            self.scan_app.set(Some(appid));
+        } else {
+            let _ = self.mac.set_channel(self.scan_saved_channel.get());
+            self.mac.config_commit();
+        }
+        result
     }
 
-    /// Schedule the next transmission if there is one pending. Performs the
-    /// transmission asynchronously, returning any errors via callbacks.
-    #[inline]
-    fn do_next_tx_async(&self) {
-        self.get_next_tx_if_idle().map(|appid| self.perform_tx_async(appid));
+    /// Switches to `channel` and measures the signal strength on it. The
+    /// radio only performs the measurement once the channel is configured.
+    fn measure_channel(&self, channel: u8) -> ReturnCode {
+        let result = self.mac.set_channel(channel);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        self.mac.config_commit();
+        self.mac.read_rssi()
     }
 
-    /// Schedule the next transmission if there is one pending. If the next
-    /// transmission happens to be the one that was just queued, then the
-    /// transmission is synchronous. Hence, errors must be returned immediately.
-    /// On the other hand, if it is some other app, then return any errors via
-    /// callbacks.
-    #[inline]
-    fn do_next_tx_sync(&self, new_appid: AppId) -> ReturnCode {
-        self.get_next_tx_if_idle()
-            .map(|appid| if appid == new_appid {
-                self.perform_tx_sync(appid)
+    /// Records the signal strength measured by the energy detection scan and
+    /// moves on to the next channel, or ends the scan after the last one.
+    fn scan_rssi_done(&self, rssi: i8, result: ReturnCode) {
+        let channel = self.scan_channel.get();
+        let mut result = result;
+        if result == ReturnCode::SUCCESS {
+            let mut energies = self.scan_results.get();
+            energies[(channel - mac::MIN_CHANNEL) as usize] = rssi;
+            self.scan_results.set(energies);
+            if ((channel + 1 - mac::MIN_CHANNEL) as usize) < mac::NUM_CHANNELS {
+                self.scan_channel.set(channel + 1);
+                result = self.measure_channel(channel + 1);
+                if result == ReturnCode::SUCCESS {
+                    return;
+                }
+            }
+        }
+        self.finish_scan(result);
+    }
+
+    /// Ends the energy detection scan, returning to the original channel and
+    /// reporting `result` and the quietest channel to the app that started
+    /// it, and resumes app transmissions.
+    fn finish_scan(&self, result: ReturnCode) {
+        let _ = self.mac.set_channel(self.scan_saved_channel.get());
+        self.mac.config_commit();
+        let energies = self.scan_results.get();
+        let mut quietest = 0;
+        for (i, energy) in energies.iter().enumerate() {
+            if *energy < energies[quietest] {
+                quietest = i;
+            }
+        }
+        let quietest = mac::MIN_CHANNEL as usize + quietest;
+        self.scan_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.scan_callback
+                    .take()
+                    .map(|mut cb| cb.schedule(result.into(), quietest, 0));
+            });
+        });
+        self.do_next_tx_async();
+    }
+
+    /// Ends the loopback self-test, reporting `result` to the app that
+    /// started it, and resumes app transmissions.
+    fn finish_loopback(&self, result: ReturnCode) {
+        self.cancel_timer(Timer::Loopback);
+        self.loopback_tx.set(false);
+        let _ = self.mac.set_loopback(false);
+        self.loopback_app.take().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.loopback_callback
+                    .take()
+                    .map(|mut cb| cb.schedule(result.into(), 0, 0));
+            });
+        });
+        self.do_next_tx_async();
+    }
+
+    // Frame aggregation functions
+
+    /// Appends the payload in `app_write` to the app's aggregated frame. If it
+    /// cannot join the sub-frames already buffered, because it has a different
+    /// destination or security or does not fit, the buffered frame is sent
+    /// instead and EBUSY is returned so that the app retries once it has been
+    /// transmitted. The frame is also sent once it is full. Returns ENOMEM if
+    /// no buffer is free to start a new frame in.
+    fn aggregate_tx(&self,
+                    appid: AppId,
+                    app: &mut App,
+                    next_tx: (MacAddress, Option<(SecurityLevel, KeyId)>))
+                    -> ReturnCode {
+        let payload_len = match app.tx_payload_len() {
+            Some(payload_len) => payload_len,
+            None => {
+                return ReturnCode::EINVAL;
+            }
+        };
+        // Each sub-frame is its length byte followed by the payload
+        let sub_len = 1 + payload_len;
+        if 1 + sub_len > MAX_AGGREGATE_LEN {
+            return ReturnCode::ESIZE;
+        }
+        if app.agg_len > 0 &&
+           (app.agg_tx != Some(next_tx) || app.agg_len + sub_len > MAX_AGGREGATE_LEN) {
+            Self::flush_aggregate(app);
+            let result = self.do_next_tx_sync(appid);
+            return if result == ReturnCode::SUCCESS {
+                ReturnCode::EBUSY
             } else {
-                self.perform_tx_async(appid);
+                result
+            };
+        }
+
+        let index = match app.agg_buf {
+            Some(index) => index,
+            None => {
+                let index = match self.agg_pool.claim(appid) {
+                    Some(index) => index,
+                    None => {
+                        return ReturnCode::ENOMEM;
+                    }
+                };
+                self.agg_pool.map(index, |agg_buf| agg_buf[0] = AGGREGATE_DISPATCH);
+                app.agg_buf = Some(index);
+                app.agg_len = 1;
+                app.agg_tx = Some(next_tx);
+                app.agg_started = self.alarm.now();
+                index
+            }
+        };
+        let off = app.agg_len;
+        app.with_tx_payload(|payload| {
+            self.agg_pool.map(index, |agg_buf| {
+                agg_buf[off] = payload_len as u8;
+                agg_buf[off + 1..off + sub_len].copy_from_slice(payload);
+            })
+        });
+        app.agg_len += sub_len;
+
+        if app.agg_len + 1 >= MAX_AGGREGATE_LEN {
+            // Not even an empty sub-frame fits anymore
+            Self::flush_aggregate(app);
+            self.do_next_tx_sync(appid)
+        } else {
+            self.arm_aggregate_timer();
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Queues the app's aggregated frame for transmission.
+    fn flush_aggregate(app: &mut App) {
+        if app.agg_len > 0 && app.pending_tx.is_none() {
+            app.pending_tx = app.agg_tx.take();
+            app.pending_aggregate = true;
+        }
+    }
+
+    /// Starts the flush timer for the oldest partially filled aggregated
+    /// frame, if any.
+    fn arm_aggregate_timer(&self) {
+        let now = self.alarm.now();
+        let timeout = Self::ms_to_tics(self.agg_timeout.get());
+        let mut remaining: Option<u32> = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| if app.agg_len > 0 && app.pending_tx.is_none() {
+                let elapsed = now.wrapping_sub(app.agg_started);
+                let left = if elapsed >= timeout { 0 } else { timeout - elapsed };
+                remaining = Some(remaining.map_or(left, |r| min(r, left)));
+            });
+        }
+        match remaining {
+            Some(tics) => self.start_timer_tics(Timer::AggregateFlush, tics),
+            None => self.cancel_timer(Timer::AggregateFlush),
+        }
+    }
+
+    /// Copies the next undelivered received sub-frame into the app's read
+    /// buffer and schedules its rx callback, if the app is ready for it. The
+    /// buffer holding the sub-frames is released once all are delivered.
+    fn deliver_subframe(&self, app: &mut App) {
+        let index = match app.rx_subframes {
+            Some(index) => index,
+            None => {
+                return;
+            }
+        };
+        if app.rx_subframes_off >= app.rx_subframes_len {
+            self.agg_pool.release(index);
+            app.rx_subframes = None;
+            return;
+        }
+        if app.rx_callback.is_none() {
+            return;
+        }
+        let mut rbuf = match app.app_read.take() {
+            Some(rbuf) => rbuf,
+            None => {
+                return;
+            }
+        };
+        let prefix = if app.rx_length_prefix { 1 } else { 0 };
+        if rbuf.len() < prefix + 2 {
+            if app.persistent_buffers {
+                app.app_read = Some(rbuf);
+            }
+            return;
+        }
+
+        let off = app.rx_subframes_off;
+        let left = app.rx_subframes_len - off - 1;
+
+        // There is no MAC header, so the payload directly follows the data
+        // offset and length bytes
+        let sub_len = {
+            let rbuf = rbuf.as_mut();
+            self.agg_pool
+                .map(index, |subframes| {
+                    let sub_len = min(subframes[off] as usize, left);
+                    let len = min(rbuf.len() - prefix - 2, sub_len);
+                    let start = off + 1;
+                    rbuf[prefix] = 2;
+                    rbuf[prefix + 1] = len as u8;
+                    rbuf[prefix + 2..prefix + 2 + len]
+                        .copy_from_slice(&subframes[start..start + len]);
+                    if prefix > 0 {
+                        rbuf[0] = (len + 2) as u8;
+                    }
+                    sub_len
+                })
+                .unwrap_or(left)
+        };
+        app.rx_subframes_off = off + 1 + sub_len;
+        if app.rx_subframes_off >= app.rx_subframes_len {
+            self.agg_pool.release(index);
+            app.rx_subframes = None;
+        }
+        if app.persistent_buffers {
+            app.app_read = Some(rbuf);
+        }
+        let (pans, dst_addr, src_addr) = app.rx_subframes_args;
+        app.rx_callback.take().map(|mut cb| cb.schedule(pans, dst_addr, src_addr));
+    }
+
+    // Duty cycle functions
+
+    /// Length of one bucket of the duty cycle window, in alarm tics.
+    fn airtime_bucket_len(&self) -> u32 {
+        Self::ms_to_tics(self.duty_cycle_window.get() / DUTY_CYCLE_BUCKETS as u32)
+    }
+
+    /// Moves the current airtime bucket forward to the present, clearing the
+    /// buckets that fell out of the window.
+    fn advance_airtime_buckets(&self) {
+        let now = self.alarm.now();
+        let bucket_len = self.airtime_bucket_len();
+        let mut buckets = self.airtime_buckets.get();
+        let mut bucket = self.airtime_bucket.get();
+        let mut start = self.airtime_bucket_start.get();
+        if now.wrapping_sub(start) >= bucket_len * DUTY_CYCLE_BUCKETS as u32 {
+            // The whole window has passed
+            buckets = [0; DUTY_CYCLE_BUCKETS];
+            start = now;
+        }
+        while now.wrapping_sub(start) >= bucket_len && bucket_len > 0 {
+            bucket = (bucket + 1) % DUTY_CYCLE_BUCKETS;
+            buckets[bucket] = 0;
+            start = start.wrapping_add(bucket_len);
+        }
+        self.airtime_buckets.set(buckets);
+        self.airtime_bucket.set(bucket);
+        self.airtime_bucket_start.set(start);
+    }
+
+    /// Accounts for `us` microseconds spent transmitting.
+    fn record_airtime(&self, us: u32) {
+        self.advance_airtime_buckets();
+        let mut buckets = self.airtime_buckets.get();
+        let bucket = self.airtime_bucket.get();
+        buckets[bucket] = buckets[bucket].saturating_add(us);
+        self.airtime_buckets.set(buckets);
+    }
+
+    /// Estimates the time on air of a frame with the given payload length
+    /// and the smallest possible MAC header, in microseconds.
+    fn estimate_airtime(payload_len: usize) -> u32 {
+        let octets = PHY_OVERHEAD_OCTETS + radio::MIN_MHR_SIZE + payload_len + radio::MFR_SIZE;
+        octets as u32 * OCTET_AIRTIME_US
+    }
+
+    /// Estimates the energy in microjoules that transmitting for `us`
+    /// microseconds takes at the configured transmission power. The supply
+    /// current is interpolated between the RF233's 7.2 mA at -17 dBm and
+    /// 13.8 mA at 4 dBm.
+    fn estimate_tx_energy(&self, us: u32) -> u32 {
+        let power = max(min(self.mac.get_tx_power(), 4), -17);
+        let current_ua = 7200 + (power as i32 + 17) as u32 * 6600 / 21;
+        (us as u64 * current_ua as u64 * SUPPLY_MV as u64 / 1_000_000_000) as u32
+    }
+
+    /// Deducts the energy of `us` microseconds spent transmitting from the
+    /// energy budget, if there is one.
+    fn charge_tx_energy(&self, us: u32) {
+        let energy = self.estimate_tx_energy(us);
+        self.energy_budget.set(self.energy_budget.get().map(|left| left.saturating_sub(energy)));
+    }
+
+    /// Whether a transmission with the given airtime is permitted by both the
+    /// duty cycle and the energy budget.
+    fn tx_permitted(&self, airtime: u32) -> bool {
+        let energy = self.estimate_tx_energy(airtime);
+        self.duty_cycle_wait(airtime).is_none() &&
+        self.energy_budget.get().map_or(true, |left| energy <= left)
+    }
+
+    /// Returns the time in milliseconds until a transmission with the given
+    /// airtime is permitted by the duty cycle, or `None` if it is permitted
+    /// now.
+    fn duty_cycle_wait(&self, airtime: u32) -> Option<u32> {
+        let duty_cycle = self.duty_cycle.get();
+        if duty_cycle == 0 {
+            return None;
+        }
+        self.advance_airtime_buckets();
+        let buckets = self.airtime_buckets.get();
+        let used: u64 = buckets.iter().map(|us| *us as u64).sum();
+        let allowed = self.duty_cycle_window.get() as u64 * 1000 * duty_cycle as u64 / 100;
+        if used + airtime as u64 <= allowed {
+            return None;
+        }
+
+        // Find how many of the oldest buckets need to leave the window before
+        // enough airtime is freed up
+        let excess = used + airtime as u64 - allowed;
+        let current = self.airtime_bucket.get();
+        let mut freed: u64 = 0;
+        for age in 1..DUTY_CYCLE_BUCKETS + 1 {
+            freed += buckets[(current + age) % DUTY_CYCLE_BUCKETS] as u64;
+            if freed >= excess {
+                let elapsed = self.alarm.now().wrapping_sub(self.airtime_bucket_start.get());
+                let wait = (age as u32 * self.airtime_bucket_len()).saturating_sub(elapsed);
+                return Some(Self::tics_to_ms(wait));
+            }
+        }
+        // The airtime does not fit in any window
+        Some(self.duty_cycle_window.get())
+    }
+
+    // Noise floor estimation functions
+
+    /// Starts or stops periodic noise floor sampling. An `interval` of 0
+    /// stops sampling.
+    fn set_noise_interval(&self, interval: u32) {
+        self.noise_interval.set(interval);
+        if interval > 0 {
+            self.start_timer(Timer::NoiseSample, interval);
+        } else {
+            self.cancel_timer(Timer::NoiseSample);
+        }
+    }
+
+    /// Records a new noise floor sample in dBm.
+    fn add_noise_sample(&self, rssi: i8) {
+        let next = self.noise_next.get();
+        self.noise_samples.map(|samples| samples[next] = rssi);
+        self.noise_next.set((next + 1) % MAX_NOISE_WINDOW);
+        self.noise_count.set(min(self.noise_count.get() + 1, MAX_NOISE_WINDOW));
+    }
+
+    /// Averages the most recent samples, up to the configured window, into a
+    /// noise floor estimate in dBm. Returns `None` if there are no samples.
+    fn get_noise_floor(&self) -> Option<i8> {
+        let count = min(self.noise_count.get(), self.noise_window.get());
+        if count == 0 {
+            return None;
+        }
+        let next = self.noise_next.get();
+        self.noise_samples.map(|samples| {
+            let mut sum: i32 = 0;
+            for i in 0..count {
+                let index = (next + MAX_NOISE_WINDOW - 1 - i) % MAX_NOISE_WINDOW;
+                sum += samples[index] as i32;
+            }
+            (sum / count as i32) as i8
+        })
+    }
+
+    /// Checks whether a frame with the same source address and sequence
+    /// number was received recently, and remembers this frame otherwise,
+    /// replacing the oldest remembered one. Frames without a source address
+    /// or sequence number are never duplicates.
+    fn seen_recently(&self, header: &Header) -> bool {
+        let seen = match (header.src_addr, header.seq) {
+            (Some(src_addr), Some(seq)) => (src_addr, seq),
+            _ => return false,
+        };
+        let mut frames = self.seen_frames.get();
+        if frames.contains(&Some(seen)) {
+            return true;
+        }
+        let next = self.seen_next.get();
+        frames[next] = Some(seen);
+        self.seen_frames.set(frames);
+        self.seen_next.set((next + 1) % MAX_SEEN_FRAMES);
+        false
+    }
+
+    /// Records a radio event in the trace ring if tracing is enabled,
+    /// replacing the oldest one.
+    fn trace(&self, event: TraceEvent, detail: u8, arg: u16) {
+        if !self.tracing.get() {
+            return;
+        }
+        let mut trace = self.trace.get();
+        let next = self.trace_next.get();
+        trace[next] = Some((Self::tics_to_ms(self.alarm.now()), event, detail, arg));
+        self.trace.set(trace);
+        self.trace_next.set((next + 1) % TRACE_LEN);
+    }
+
+    /// Adds a received frame to the frame counters. Sources beyond the first
+    /// `MAX_COUNTED_SOURCES` distinct ones are not tracked.
+    fn count_frame(&self, header: &Header, frame_len: usize) {
+        self.counted_frames.set(self.counted_frames.get().wrapping_add(1));
+        self.counted_octets.set(self.counted_octets.get().wrapping_add(frame_len as u32));
+        if let Some(src_addr) = header.src_addr {
+            let mut sources = self.counted_sources.get();
+            if !sources.contains(&Some(src_addr)) {
+                if let Some(slot) = sources.iter_mut().find(|source| source.is_none()) {
+                    *slot = Some(src_addr);
+                }
+                self.counted_sources.set(sources);
+            }
+        }
+    }
+
+    /// Adds the signal strength of a received frame to the histogram.
+    fn add_rssi_sample(&self, rssi: i8) {
+        let bin = if (rssi as i16) < RSSI_BIN_BASE {
+            0
+        } else {
+            min(1 + ((rssi as i16 - RSSI_BIN_BASE) / RSSI_BIN_WIDTH) as usize,
+                RSSI_BINS - 1)
+        };
+        let mut histogram = self.rssi_histogram.get();
+        histogram[bin] = histogram[bin].saturating_add(1);
+        self.rssi_histogram.set(histogram);
+    }
+
+    /// Adds the signal strength of a received frame to the moving average
+    /// that estimates the link quality. Each new frame contributes a quarter.
+    fn update_link_rssi(&self, rssi: i8) {
+        let average = self.link_rssi.get().map_or(rssi as i16, |average| {
+            (3 * average + rssi as i16) / 4
+        });
+        self.link_rssi.set(Some(average));
+    }
+
+    /// Returns the largest payload that may currently be transmitted. This is
+    /// reduced while payload sizes are adapted to the link quality and the
+    /// link is marginal, either because received frames are weak or because
+    /// several transmissions in a row were not acknowledged.
+    fn payload_limit(&self) -> usize {
+        let reduced = self.marginal_payload_len.get();
+        if reduced == 0 {
+            return MAX_PAYLOAD_LEN;
+        }
+        let weak = self.link_rssi
+            .get()
+            .map_or(false, |average| average < self.marginal_rssi.get() as i16);
+        let failing = self.link_failures.get() >= self.marginal_failures.get();
+        if weak || failing {
+            reduced
+        } else {
+            MAX_PAYLOAD_LEN
+        }
+    }
+
+    /// Clears the frame counters.
+    fn reset_frame_counters(&self) {
+        self.counted_frames.set(0);
+        self.counted_octets.set(0);
+        self.counted_sources.set([None; MAX_COUNTED_SOURCES]);
+    }
+
+    /// Notifies every app that subscribed to channel changes that the
+    /// configured channel is now `channel`.
+    fn notify_channel_changed(&self, channel: u8) {
+        self.apps.each(|app| {
+            app.channel_callback
+                .as_mut()
+                .map(|cb| cb.schedule(channel as usize, 0, 0));
+        });
+    }
+
+    /// Checks in software whether a received frame was addressed to this
+    /// node, either directly or by broadcast, regardless of whether the radio
+    /// filtered it.
+    fn addressed_to_us(&self, header: &Header) -> bool {
+        let pan_matches = header.dst_pan
+            .map_or(true, |pan| pan == 0xffff || pan == self.mac.get_pan());
+        let addr_matches = match header.dst_addr {
+            Some(MacAddress::Short(addr)) => addr == 0xffff || addr == self.mac.get_address(),
+            Some(MacAddress::Long(addr)) => addr == self.mac.get_address_long(),
+            // Frames without a destination are implicitly addressed to the
+            // PAN coordinator
+            None => self.mac.is_pan_coordinator(),
+        };
+        pan_matches && addr_matches
+    }
+
+    /// Keeps a received frame in a buffer from `rx_hold_pool` until the app
+    /// fetches it. Returns false if every slot of the app is taken or no
+    /// buffer is free, in which case the frame is dropped.
+    fn hold_frame(&self,
+                  appid: AppId,
+                  app: &mut App,
+                  buf: &[u8],
+                  data_offset: usize,
+                  data_len: usize)
+                  -> bool {
+        if app.rx_held_len == RX_HOLD_SLOTS {
+            return false;
+        }
+        let index = match self.rx_hold_pool.claim(appid) {
+            Some(index) => index,
+            None => {
+                return false;
+            }
+        };
+        let len = min(data_offset + data_len, RX_HOLD_BUF_LEN);
+        let offset = min(data_offset, len);
+        self.rx_hold_pool.map(index, |held| held[..len].copy_from_slice(&buf[..len]));
+        let tail = (app.rx_held_head + app.rx_held_len) % RX_HOLD_SLOTS;
+        app.rx_held[tail] = index;
+        app.rx_held_payloads[tail] = (offset, len - offset);
+        app.rx_held_len += 1;
+        true
+    }
+
+    /// Copies the oldest held frame into `app_read`, in the same format as a
+    /// delivered frame, and releases its buffer. Returns the number of bytes
+    /// copied.
+    fn fetch_held_frame(&self, app: &mut App) -> ReturnCode {
+        if app.rx_held_len == 0 {
+            return ReturnCode::FAIL;
+        }
+        let prefix = if app.rx_length_prefix { 1 } else { 0 };
+        let slot = app.rx_held_head;
+        let index = app.rx_held[slot];
+        let (data_offset, data_len) = app.rx_held_payloads[slot];
+        let len = data_offset + data_len;
+        match app.app_read {
+            Some(ref mut rbuf) => {
+                let rbuf = rbuf.as_mut();
+                if rbuf.len() < prefix + len {
+                    return ReturnCode::ESIZE;
+                }
+                self.rx_hold_pool.map(index, |frame| {
+                    rbuf[prefix..prefix + len].copy_from_slice(&frame[..len]);
+                });
+                rbuf[prefix] = data_offset as u8;
+                rbuf[prefix + 1] = data_len as u8;
+                if prefix > 0 {
+                    rbuf[0] = len as u8;
+                }
+            }
+            None => return ReturnCode::EINVAL,
+        }
+        self.rx_hold_pool.release(index);
+        app.rx_held_head = (app.rx_held_head + 1) % RX_HOLD_SLOTS;
+        app.rx_held_len -= 1;
+        // Guarantee that the value is positive by adding 1
+        ReturnCode::SuccessWithValue { value: prefix + len + 1 }
+    }
+
+    /// Drops every frame held for the app and releases their buffers.
+    fn discard_held_frames(&self, app: &mut App) {
+        for i in 0..app.rx_held_len {
+            self.rx_hold_pool.release(app.rx_held[(app.rx_held_head + i) % RX_HOLD_SLOTS]);
+        }
+        app.rx_held_head = 0;
+        app.rx_held_len = 0;
+    }
+
+    /// Delivers a received frame that passed the app's filters to the app,
+    /// in the format described in `RxClient::receive`.
+    fn deliver_frame(&self,
+                     appid: AppId,
+                     app: &mut App,
+                     buf: &[u8],
+                     header: &Header,
+                     data_offset: usize,
+                     data_len: usize,
+                     addressed_to_us: bool,
+                     crc_valid: bool) {
+        if app.rx_hold {
+            if !self.hold_frame(appid, app, buf, data_offset, data_len) {
+                RadioStats::increment(&self.stats.rx_no_buffer);
+                self.trace(TraceEvent::Dropped, TRACE_DROP_HOLD_FULL, 0);
+            }
+            return;
+        }
+        if app.rx_interval != 0 {
+            let now = self.rx_timestamp.get();
+            let interval = Self::ms_to_tics(app.rx_interval);
+            if app.rx_last_callback.map_or(false, |last| now.wrapping_sub(last) < interval) {
+                app.rx_suppressed += 1;
+                if !app.rx_interval_hold ||
+                   !self.hold_frame(appid, app, buf, data_offset, data_len) {
+                    self.trace(TraceEvent::Dropped, TRACE_DROP_RATE_LIMIT, 0);
+                }
+                return;
+            }
+            app.rx_last_callback = Some(now);
+        }
+        if app.aggregate && data_len > 0 && buf[data_offset] == AGGREGATE_DISPATCH {
+            // Sub-frames not delivered yet are replaced by the new ones
+            let index = match app.rx_subframes.or_else(|| self.agg_pool.claim(appid)) {
+                Some(index) => index,
+                None => {
+                    RadioStats::increment(&self.stats.rx_no_buffer);
+                    self.trace(TraceEvent::Dropped, TRACE_DROP_NO_BUFFER, 0);
+                    return;
+                }
+            };
+            let len = min(data_len - 1, MAX_AGGREGATE_LEN);
+            self.agg_pool.map(index, |subframes| {
+                subframes[..len].copy_from_slice(&buf[data_offset + 1..data_offset + 1 + len]);
+            });
+            app.rx_subframes = Some(index);
+            app.rx_subframes_len = len;
+            app.rx_subframes_off = 0;
+            app.rx_quality = Some((self.mac.get_rx_rssi(), self.mac.get_rx_lqi()));
+            let mut dst_addr = encode_address(&header.dst_addr);
+            if app.check_dst && addressed_to_us {
+                dst_addr |= ADDRESSED_TO_US;
+            }
+            if !crc_valid {
+                dst_addr |= CRC_INVALID;
+            }
+            dst_addr |= app.take_suppressed();
+            app.rx_subframes_args = (encode_pans(&header.dst_pan, &header.src_pan),
+                                     dst_addr,
+                                     encode_address(&header.src_addr));
+            self.deliver_subframe(app);
+            return;
+        }
+        if app.persistent_buffers && app.rx_callback.is_none() {
+            // The app has not consumed the previous frame yet, so do not
+            // overwrite it
+            return;
+        }
+        let prefix = if app.rx_length_prefix { 1 } else { 0 };
+        let mut read = app.app_read.take();
+        if read.is_none() {
+            RadioStats::increment(&self.stats.rx_no_buffer);
+            self.trace(TraceEvent::Dropped, TRACE_DROP_NO_BUFFER, 0);
+            // Still tell the app that a frame arrived, which it can tell apart
+            // from a delivered one by the NO_BUFFER bit
+            let pans = encode_pans(&header.dst_pan, &header.src_pan);
+            let dst_addr = encode_address(&header.dst_addr) | NO_BUFFER |
+                           app.take_suppressed();
+            let src_addr = encode_address(&header.src_addr);
+            app.rx_callback.take().map(|mut cb| cb.schedule(pans, dst_addr, src_addr));
+            return;
+        }
+        let delivered = read.as_mut()
+            .map_or(false, |rbuf| {
+                let rbuf = rbuf.as_mut();
+                if rbuf.len() < prefix + 2 {
+                    return false;
+                }
+                let truncated = rbuf.len() - prefix < data_offset + data_len;
+                if truncated {
+                    self.oversized_frames.set(self.oversized_frames.get() + 1);
+                    match app.oversized {
+                        OversizedPolicy::Drop => return false,
+                        OversizedPolicy::Truncate => {}
+                        OversizedPolicy::TruncateAndLog => {
+                            debug!("802.15.4: truncated {} byte frame to {} bytes",
+                                   data_offset + data_len,
+                                   rbuf.len() - prefix);
+                        }
+                    }
+                }
+                let len = min(rbuf.len() - prefix, data_offset + data_len);
+                rbuf[prefix..prefix + len].copy_from_slice(&buf[..len]);
+                rbuf[prefix] = data_offset as u8;
+                rbuf[prefix + 1] = data_len as u8;
+                if prefix > 0 {
+                    rbuf[0] = len as u8;
+                }
+                app.app_rx_ies.as_mut().map(|ies| encode_ies(header, ies.as_mut()));
+                app.rx_quality = Some((self.mac.get_rx_rssi(), self.mac.get_rx_lqi()));
+                let timestamp = self.rx_timestamp.get();
+                app.app_rx_meta
+                    .as_mut()
+                    .map(|meta| encode_rx_metadata(header, timestamp, meta.as_mut()));
+
+                // Encode useful parts of the header in 3 usizes
+                let pans = encode_pans(&header.dst_pan, &header.src_pan);
+                let mut dst_addr = encode_address(&header.dst_addr);
+                if app.check_dst && addressed_to_us {
+                    dst_addr |= ADDRESSED_TO_US;
+                }
+                if truncated {
+                    dst_addr |= TRUNCATED;
+                }
+                if !crc_valid {
+                    dst_addr |= CRC_INVALID;
+                }
+                dst_addr |= app.take_suppressed();
+                let src_addr = encode_address(&header.src_addr);
+                app.rx_callback.take().map(|mut cb| cb.schedule(pans, dst_addr, src_addr));
+                true
+            });
+        if app.persistent_buffers || !delivered {
+            app.app_read = read;
+        }
+    }
+
+    /// If the driver is currently idle and there are pending transmissions,
+    /// pick an app with a pending transmission and return its `AppId`.
+    /// The app with the highest priority is picked, and apps of equal
+    /// priority take turns, starting after the app that transmitted last.
+    fn get_next_tx_if_idle(&self) -> Option<AppId> {
+        if self.radio_in_use() {
+            return None;
+        }
+        let next_idx = self.last_tx_idx.get().wrapping_add(1);
+        let mut pending_app: Option<(AppId, u8, usize)> = None;
+        for app in self.apps.iter() {
+            app.enter(|app, _| if app.pending_tx.is_some() {
+                let appid = app.appid();
+                // Number of turns until this app would be next in line
+                let turns = appid.idx().wrapping_sub(next_idx);
+                let better = pending_app.map_or(true, |(_, priority, pending_turns)| {
+                    app.priority > priority || (app.priority == priority && turns < pending_turns)
+                });
+                if better {
+                    pending_app = Some((appid, app.priority, turns));
+                }
+            });
+        }
+        pending_app.map(|(appid, _, _)| appid)
+    }
+
+    /// Performs `appid`'s pending transmission asynchronously. If the
+    /// transmission is not successful, the error is returned to the app via its
+    /// `tx_callback`. Assumes that the driver is currently idle and the app has
+    /// a pending transmission.
+    #[inline]
+    fn perform_tx_async(&self, appid: AppId) {
+        let result = self.perform_tx_sync(appid);
+        if result != ReturnCode::SUCCESS {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.tx_callback
+                    .take()
+                    .map(|mut cb| cb.schedule(result.into(), 0, 0));
+            });
+        }
+    }
+
+    /// Performs `appid`'s pending transmission synchronously. The result is
+    /// returned immediately to the app. Assumes that the driver is currently
+    /// idle and the app has a pending transmission.
+    #[inline]
+    fn perform_tx_sync(&self, appid: AppId) -> ReturnCode {
+        self.do_with_app(appid, |app| {
+            let (dst_addr, security_needed) = match app.pending_tx.take() {
+                Some(pending_tx) => pending_tx,
+                None => {
+                    return ReturnCode::SUCCESS;
+                }
+            };
+            let broadcast = dst_addr == MacAddress::Short(0xffff);
+            // An aggregated frame is consumed even if it fails to send
+            let aggregate = app.pending_aggregate;
+            let agg_len = app.agg_len;
+            let agg_buf = if aggregate { app.agg_buf.take() } else { None };
+            if aggregate {
+                app.pending_aggregate = false;
+                app.agg_len = 0;
+            }
+            let queued = app.pending_queued.take();
+            let empty = app.pending_empty;
+            app.pending_empty = false;
+            let no_ack = broadcast || app.pending_no_ack;
+            app.pending_no_ack = false;
+            let result = self.kernel_tx
+                .take()
+                .map_or(ReturnCode::ENOMEM, |kbuf| {
+                    // Prepare the frame headers
+                    let pan = self.mac.get_pan();
+                    // Broadcasts also go to the broadcast PAN
+                    let dst_pan = if broadcast { 0xffff } else { pan };
+                    let src_addr = if self.src_addr_long.get() {
+                        MacAddress::Long(self.mac.get_address_long())
+                    } else {
+                        MacAddress::Short(self.mac.get_address())
+                    };
+                    let prepared = {
+                        let mut hies = [HeaderIE::default(); MAX_HEADER_IES];
+                        let mut pies = [PayloadIE::default(); MAX_PAYLOAD_IES];
+                        let ies = app.app_tx_ies
+                            .as_ref()
+                            .map_or(Some((0, 0)),
+                                    |ies| decode_ies(ies.as_ref(), &mut hies, &mut pies));
+                        match ies {
+                            Some((num_hies, num_pies)) => {
+                                self.mac.prepare_data_frame_with_ies(kbuf,
+                                                                     dst_pan,
+                                                                     dst_addr,
+                                                                     pan,
+                                                                     src_addr,
+                                                                     security_needed,
+                                                                     &hies[..num_hies],
+                                                                     &pies[..num_pies])
+                            }
+                            None => Err(kbuf),
+                        }
+                    };
+                    let mut frame = match prepared {
+                        Ok(frame) => frame,
+                        Err(kbuf) => {
+                            self.kernel_tx.replace(kbuf);
+                            return ReturnCode::FAIL;
+                        }
+                    };
+                    if no_ack {
+                        frame.set_ack_requested(false);
+                    }
+
+                    // Append the payload: there must be one
+                    let result = if let Some(index) = agg_buf {
+                        self.agg_pool
+                            .map(index, |agg_buf| frame.append_payload(&agg_buf[..agg_len]))
+                            .unwrap_or(ReturnCode::FAIL)
+                    } else if let Some((index, len)) = queued {
+                        self.tx_queue_pool
+                            .map(index, |payload| frame.append_payload(&payload[..len]))
+                            .unwrap_or(ReturnCode::FAIL)
+                    } else if empty {
+                        ReturnCode::SUCCESS
+                    } else {
+                        app.with_tx_payload(|payload| frame.append_payload(payload))
+                            .unwrap_or(ReturnCode::EINVAL)
+                    };
+                    if result != ReturnCode::SUCCESS {
+                        // Recover the buffer, or no further frames could be sent
+                        self.kernel_tx.replace(frame.into_buf());
+                        // The frame only runs out of room if the payload is too
+                        // long
+                        return if result == ReturnCode::ENOMEM {
+                            ReturnCode::ESIZE
+                        } else {
+                            result
+                        };
+                    }
+
+                    // Finally, transmit the frame
+                    let (result, mbuf) = self.mac.transmit(frame);
+                    if let Some(buf) = mbuf {
+                        self.kernel_tx.replace(buf);
+                    }
+                    result
+                });
+            agg_buf.map(|index| self.agg_pool.release(index));
+            queued.map(|(index, _)| self.tx_queue_pool.release(index));
+            if result == ReturnCode::SUCCESS {
+                RadioStats::increment(&self.stats.tx_frames);
+                self.current_app.set(Some(appid));
+                self.last_tx_idx.set(appid.idx());
+                self.tx_started.set(self.alarm.now());
+                self.tx_no_ack.set(no_ack);
+                self.last_tx_error.set(None);
+                self.trace(TraceEvent::TxStarted, appid.idx() as u8, 0);
+            } else {
+                RadioStats::increment(&self.stats.tx_failures);
+            }
+            // The next queued transmission is sent once the radio is free
+            self.dequeue_tx(app);
+            result
+        })
+    }
+
+    /// Abort the frame in flight, if the radio has not sent it yet. Returns
+    /// whether it was aborted, in which case no app is in flight any more and
+    /// `send_done` never reports it.
+    fn abort_in_flight(&self) -> bool {
+        let (result, mbuf) = self.mac.abort_transmit();
+        if let Some(buf) = mbuf {
+            self.kernel_tx.replace(buf);
+        }
+        if result != ReturnCode::SUCCESS {
+            return false;
+        }
+        self.trace(TraceEvent::TxDone, isize::from(ReturnCode::ECANCEL) as u8, 0);
+        RadioStats::increment(&self.stats.tx_failures);
+        self.current_app.set(None);
+        true
+    }
+
+    /// Commit the configuration to the radio, notifying the apps subscribed to
+    /// configuration changes once it takes effect.
+    fn config_commit(&self) {
+        self.config_requested.set(true);
+        self.mac.config_commit();
+    }
+
+    /// Pass frames with an invalid FCS up from the MAC as long as at least one
+    /// app wants to receive them.
+    fn update_deliver_crc_errors(&self) {
+        let deliver = Cell::new(false);
+        self.apps.each(|app| if app.rx_crc_errors {
+            deliver.set(true);
+        });
+        self.mac.set_deliver_crc_errors(deliver.get());
+    }
+
+    /// Queues a transmission behind the pending one, copying its payload out
+    /// of `app_write` into a buffer of `tx_queue_pool`. Assumes that the
+    /// queue of the app is not full.
+    fn enqueue_tx(&self,
+                  appid: AppId,
+                  app: &mut App,
+                  next_tx: (MacAddress, Option<(SecurityLevel, KeyId)>),
+                  no_ack: bool)
+                  -> ReturnCode {
+        let payload_len = match app.tx_payload_len() {
+            Some(payload_len) => payload_len,
+            None => {
+                return ReturnCode::EINVAL;
+            }
+        };
+        if payload_len > TX_QUEUE_BUF_LEN {
+            return ReturnCode::ESIZE;
+        }
+        let queued = QueuedTx {
+            tx: next_tx,
+            payload_len: payload_len,
+            no_ack: no_ack,
+        };
+        let index = match self.push_tx(appid, app, queued) {
+            Some(index) => index,
+            None => {
+                return ReturnCode::ENOMEM;
+            }
+        };
+        app.with_tx_payload(|payload| {
+            self.tx_queue_pool.map(index, |buf| buf[..payload.len()].copy_from_slice(payload))
+        });
+        ReturnCode::SUCCESS
+    }
+
+    /// Appends a transmission to the queue of the app, returning the index of
+    /// the buffer borrowed for its payload, or `None` if no buffer is free.
+    /// Assumes that the queue of the app is not full.
+    fn push_tx(&self, appid: AppId, app: &mut App, queued: QueuedTx) -> Option<usize> {
+        self.tx_queue_pool.claim(appid).map(|index| {
+            self.tx_queued.map(|tx_queued| tx_queued[index] = Some(queued));
+            let tail = (app.tx_queue_head + app.tx_queue_len) % TX_QUEUE_LEN;
+            app.tx_queue[tail] = index;
+            app.tx_queue_len += 1;
+            index
+        })
+    }
+
+    /// Moves the oldest queued transmission of the app, if any, into
+    /// `pending_tx` once there is no pending transmission anymore.
+    fn dequeue_tx(&self, app: &mut App) {
+        if app.pending_tx.is_some() || app.tx_queue_len == 0 {
+            return;
+        }
+        let index = app.tx_queue[app.tx_queue_head];
+        app.tx_queue_head = (app.tx_queue_head + 1) % TX_QUEUE_LEN;
+        app.tx_queue_len -= 1;
+        let queued = self.tx_queued.and_then(|tx_queued| tx_queued[index]);
+        app.pending_tx = queued.map(|queued| queued.tx);
+        app.pending_no_ack = queued.map_or(false, |queued| queued.no_ack);
+        app.pending_queued = queued.map(|queued| (index, queued.payload_len));
+    }
+
+    /// Drops every transmission of the app that has not been handed to the
+    /// radio yet: the pending one, the queued ones and any buffered
+    /// sub-frames. Returns whether there were any.
+    fn cancel_tx(&self, app: &mut App) -> bool {
+        let pending = app.pending_tx.is_some() || app.tx_queue_len > 0 || app.agg_len > 0;
+        app.pending_tx = None;
+        app.pending_no_ack = false;
+        app.pending_queued.take().map(|(index, _)| self.tx_queue_pool.release(index));
+        app.pending_empty = false;
+        app.pending_aggregate = false;
+        app.agg_buf.take().map(|index| self.agg_pool.release(index));
+        app.agg_len = 0;
+        app.agg_tx = None;
+        for i in 0..app.tx_queue_len {
+            self.tx_queue_pool.release(app.tx_queue[(app.tx_queue_head + i) % TX_QUEUE_LEN]);
+        }
+        app.tx_queue_head = 0;
+        app.tx_queue_len = 0;
+        pending
+    }
+
+    /// Schedule the next transmission if there is one pending. Performs the
+    /// transmission asynchronously, returning any errors via callbacks.
+    #[inline]
+    fn do_next_tx_async(&self) {
+        self.get_next_tx_if_idle().map(|appid| self.perform_tx_async(appid));
+    }
+
+    /// Schedule the next transmission if there is one pending. If the next
+    /// transmission happens to be the one that was just queued, then the
+    /// transmission is synchronous. Hence, errors must be returned immediately.
+    /// On the other hand, if it is some other app, then return any errors via
+    /// callbacks.
+    #[inline]
+    fn do_next_tx_sync(&self, new_appid: AppId) -> ReturnCode {
+        self.get_next_tx_if_idle()
+            .map(|appid| if appid == new_appid {
+                self.perform_tx_sync(appid)
+            } else {
+                self.perform_tx_async(appid);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or(ReturnCode::SUCCESS)
+    }
+}
+
+impl<'a, A: time::Alarm + 'a> mac::DeviceProcedure for RadioDriver<'a, A> {
+    /// Gets the long address corresponding to the neighbor that matches the given
+    /// MAC address. If no such neighbor exists, returns `None`.
+    fn lookup_addr_long(&self, addr: MacAddress) -> Option<([u8; 8])> {
+        self.neighbors.and_then(|neighbors| {
+            neighbors[..self.num_neighbors.get()]
+                .iter()
+                .find(|neighbor| match addr {
+                    MacAddress::Short(addr) => addr == neighbor.short_addr,
+                    MacAddress::Long(addr) => addr == neighbor.long_addr,
+                })
+                .map(|neighbor| neighbor.long_addr)
+        })
+    }
+}
+
+impl<'a, A: time::Alarm + 'a> mac::KeyProcedure for RadioDriver<'a, A> {
+    /// Gets the key corresponding to the key that matches the given security
+    /// level `level` and key ID `key_id`. If no such key matches, returns
+    /// `None`.
+    fn lookup_key(&self, level: SecurityLevel, key_id: KeyId) -> Option<([u8; 16])> {
+        self.keys.and_then(|keys| {
+            keys[..self.num_keys.get()]
+                .iter()
+                .find(|key| key.level == level && key.key_id == key_id)
+                .map(|key| key.key)
+        })
+    }
+}
+
+impl<'a, A: time::Alarm + 'a> Driver for RadioDriver<'a, A> {
+    /// Setup buffers to read/write from.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Read buffer. Will contain the received frame.
+    /// - `1`: Write buffer. Contains the frame payload to be transmitted.
+    /// - `2`: Config buffer. Used to contain miscellaneous data associated with
+    ///        some commands because the system call parameters / return codes are
+    ///        not enough to convey the desired information.
+    /// - `3`: Transmit IE buffer. Contains information elements to insert into
+    ///        every transmitted frame in the standard format: any header IEs
+    ///        followed by any payload IEs, without list terminators. Remains
+    ///        in use until replaced; allow an empty buffer to stop inserting
+    ///        IEs. Transmissions fail unless the frame version is 2015.
+    /// - `4`: Receive IE buffer. Filled with the information elements of each
+    ///        frame copied into the read buffer, in the same format as the
+    ///        transmit IE buffer, preceded by one byte holding their length.
+    /// - `5`: Receive metadata buffer. Filled with the header fields of each
+    ///        frame copied into the read buffer, so that apps need not parse
+    ///        the MAC header themselves. Must be at least 16 bytes long:
+    ///        1 byte: the frame type, as in the frame control field +
+    ///        1 byte: 1 if the frame has a sequence number, otherwise 0 +
+    ///        1 byte: the sequence number +
+    ///        1 byte: the source addressing mode: 0 for none, 2 for short, 3
+    ///                for long +
+    ///        8 bytes: the source address, with a short address in the first
+    ///                 2 bytes, little-endian +
+    ///        2 bytes: the destination PAN ID, little-endian, or 0 if absent +
+    ///        2 bytes: the source PAN ID, little-endian, or 0 if absent.
+    ///        If the buffer is at least 20 bytes long, it is followed by:
+    ///        4 bytes: the alarm tick at which the driver received the frame,
+    ///                 little-endian, for timestamping.
+    /// - `6`: Transmit status buffer. Filled with the outcome of each of the
+    ///        app's transmissions just before its tx callback is invoked, and
+    ///        left untouched if it is shorter than 8 bytes:
+    ///        1 byte: the result, cast from signed like the transmission
+    ///                power +
+    ///        1 byte: bit 0 set if the frame was acknowledged, and bit 1 set
+    ///                if the acknowledgement had its frame pending bit set +
+    ///        1 byte: the number of retries used +
+    ///        1 byte: why the transmission failed, as reported by command
+    ///                103, or 0 if it succeeded +
+    ///        4 bytes: the alarm tick at which the transmission completed,
+    ///                 little-endian.
+    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
+        match allow_num {
+            0 | 1 | 2 | 3 | 4 | 5 | 6 => {
+                self.do_with_app(appid, |app| {
+                    match allow_num {
+                        0 => app.app_read = Some(slice),
+                        1 => app.app_write = Some(slice),
+                        2 => app.app_cfg = Some(slice),
+                        3 => app.app_tx_ies = Some(slice),
+                        4 => app.app_rx_ies = Some(slice),
+                        5 => app.app_rx_meta = Some(slice),
+                        6 => app.app_tx_status = Some(slice),
+                        _ => {}
+                    }
+                    if allow_num == 0 {
+                        self.deliver_subframe(app);
+                    }
+                    ReturnCode::SUCCESS
+                })
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup callback for when frame is received.
+    /// - `1`: Setup callback for when frame is transmitted. The arguments are
+    ///        the result, whether the frame was acknowledged, and the number
+    ///        of retries used (see command 80). Bit 1 of the second argument
+    ///        is set if the acknowledgement had its frame pending bit set,
+    ///        meaning the receiver has more data queued for this node.
+    /// - `2`: Setup callback for when the configured channel changes. The
+    ///        new channel is passed as the first argument. Unlike the other
+    ///        callbacks, this one stays registered after it fires.
+    /// - `3`: Setup callback for when a clear channel assessment started by
+    ///        command 81 completes. The arguments are the result, 1 if the
+    ///        channel was clear or 0 if it was busy, and the measured signal
+    ///        strength in dBm, cast to unsigned like the transmission power.
+    /// - `4`: Setup callback for when the loopback self-test started by
+    ///        command 87 finishes. The result is SUCCESS if the test frame was
+    ///        received intact, or FAIL if it was not received in time.
+    /// - `5`: Setup callback for when the radio is turned on or off. The first
+    ///        argument is 1 once the radio is ready to send and receive
+    ///        frames, and 0 once it has gone to sleep. If the radio is
+    ///        already on, the callback fires right away. Like the channel
+    ///        callback, this one stays registered after it fires.
+    /// - `6`: Setup callback for when an energy detection scan started by
+    ///        command 109 completes. The arguments are the result and the
+    ///        quietest channel, which had the lowest signal strength.
+    /// - `7`: Setup callback for when the configuration committed by command
+    ///        7 has taken effect in the radio, for apps that must not transmit
+    ///        before it has. The arguments are the result and which
+    ///        parameters changed since the previous commit, as a bitmask:
+    ///        bit 0: the short address (command 2).
+    ///        bit 1: the long address (command 3).
+    ///        bit 2: the PAN ID (command 4).
+    ///        bit 3: the channel (command 5).
+    ///        bit 4: the transmission power (command 6).
+    ///        Unlike most callbacks, this one stays registered after it fires.
+    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
+        match subscribe_num {
+            0 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.rx_callback = Some(callback);
+                    self.deliver_subframe(app);
+                    ReturnCode::SUCCESS
+                })
+            }
+            1 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.tx_callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+            }
+            2 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.channel_callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+            }
+            3 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.cca_callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+            }
+            4 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.loopback_callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+            }
+            5 => {
+                let on = self.mac.is_on();
+                self.do_with_app(callback.app_id(), |app| {
+                    app.ready_callback = Some(callback);
+                    if on {
+                        app.ready_callback.as_mut().map(|cb| cb.schedule(1, 0, 0));
+                    }
+                    ReturnCode::SUCCESS
+                })
+            }
+            6 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.scan_callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+            }
+            7 => {
+                self.do_with_app(callback.app_id(), |app| {
+                    app.config_callback = Some(callback);
+                    ReturnCode::SUCCESS
+                })
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// IEEE 802.15.4 MAC device control.
+    ///
+    /// For some of the below commands, one 32-bit argument is not enough to
+    /// contain the desired input parameters or output data. For those commands,
+    /// the config slice `app_cfg` is used as a channel to shuffle information
+    /// between kernel space and user space. The expected size of the slice
+    /// varies by command, and acts essentially like a custom FFI. That is, the
+    /// userspace library MUST `allow()` a buffer of the correct size, otherwise
+    /// the call is EINVAL. When used, the expected format is described below.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check. Returns the identifier of the radio chip, which
+    ///        is the part number on the RF233, if the radio reports one, or
+    ///        SUCCESS otherwise.
+    /// - `1`: Return radio status. SUCCESS/EOFF = on/off.
+    /// - `2`: Set short MAC address.
+    /// - `3`: Set long MAC address, which the radio also filters received
+    ///        frames on, once committed with command 7. Returns ESIZE if the
+    ///        config buffer is not 8 bytes long.
+    ///        app_cfg (in): 8 bytes: the long MAC address.
+    /// - `4`: Set PAN ID.
+    /// - `5`: Set channel. Apps subscribed to channel changes are notified if
+    ///        this differs from the current channel. Returns EINVAL for
+    ///        channels outside 11 to 26, and EBUSY while a transmission is in
+    ///        flight, since changing the channel would corrupt it.
+    /// - `6`: Set transmission power, in dBm. The low 8 bits of `arg1` are
+    ///        interpreted as a signed `i8`. Returns EINVAL if the radio does
+    ///        not support the power, which for the RF233 must lie between -17
+    ///        and 4 dBm. Command 12 reads back the configured power.
+    /// - `7`: Commit any configuration changes. Apps subscribed to
+    ///        configuration changes are notified once the radio has applied
+    ///        them.
+    /// - `8`: Get the short MAC address.
+    /// - `9`: Get the long MAC address.
+    ///        app_cfg (out): 8 bytes: the long MAC address.
+    /// - `10`: Get the PAN ID.
+    /// - `11`: Get the channel.
+    /// - `12`: Get the transmission power.
+    /// - `13`: Get the maximum number of neighbors.
+    /// - `14`: Get the current number of neighbors.
+    /// - `15`: Get the short address of the neighbor at an index.
+    /// - `16`: Get the long address of the neighbor at an index.
+    ///        app_cfg (out): 8 bytes: the long MAC address.
+    /// - `17`: Add a new neighbor with the given short and long address.
+    ///        app_cfg (in): 8 bytes: the long MAC address.
+    /// - `18`: Remove the neighbor at an index.
+    /// - `19`: Get the maximum number of keys.
+    /// - `20`: Get the current number of keys.
+    /// - `21`: Get the security level of the key at an index.
+    /// - `22`: Get the key id of the key at an index.
+    ///        app_cfg (out): 1 byte: the key ID mode +
+    ///                       up to 9 bytes: the key ID.
+    /// - `23`: Get the key at an index.
+    ///        app_cfg (out): 16 bytes: the key.
+    /// - `24`: Add a new key with the given descripton.
+    ///        app_cfg (in): 1 byte: the security level +
+    ///                      1 byte: the key ID mode +
+    ///                      9 bytes: the key ID (might not use all bytes) +
+    ///                      16 bytes: the key.
+    /// - `25`: Remove the key at an index.
+    /// - `26`: Transmit a frame to the given short address. If this app's
+    ///        previous transmission is still waiting for the radio, the
+    ///        payload is copied into a queue of up to 4 transmissions that are
+    ///        sent in order, and the write buffer can be reused right away.
+    ///        Returns EBUSY if the queue is full, and ENOMEM if none of the
+    ///        buffers the board shares among all apps for queued payloads is
+    ///        free. Returns EUNINSTALLED in listen-only mode (command 66).
+    ///        Command 52 reports the size of the queue. The address 0xFFFF
+    ///        broadcasts the frame to the broadcast PAN 0xFFFF. Broadcasts do
+    ///        not request an acknowledgement and are never retransmitted, so
+    ///        they complete as soon as the frame leaves the radio and the
+    ///        transmit callback reports them as not acknowledged.
+    ///        arg1: bits 0-15: the short address.
+    ///              bit 24: send to the long address in the config buffer
+    ///                      instead, exactly like command 79.
+    ///              bit 25: do not request an acknowledgement. The frame is
+    ///                      not retransmitted and completes like a broadcast.
+    ///                      Not supported with aggregation (command 38).
+    ///              The other bits are ignored.
+    ///        app_cfg (in): 1 byte: the security level +
+    ///                      10 bytes: the key ID mode and key ID, if the
+    ///                      security level is not 0.
+    /// - `27`: Set the data sequence number (DSN) of the next transmitted
+    ///        frame. This is intended for making frame sequences deterministic
+    ///        in tests. Production code should seed the DSN from a random or
+    ///        persisted value instead.
+    /// - `28`: Enable (`arg1` != 0) or disable (`arg1` == 0) prefixing each
+    ///        frame copied into the read buffer with a length byte. See
+    ///        `RxClient::receive` for the format. Disabled by default.
+    /// - `29`: Get the time in milliseconds since the transmission in flight
+    ///        was started. Returns EALREADY if there is no transmission in
+    ///        flight.
+    /// - `30`: Get the noise floor estimate, averaged over the configured
+    ///        window of periodic idle-channel RSSI samples. The estimate is in
+    ///        dBm and cast to unsigned the same way as the transmission power.
+    ///        Returns FAIL if no samples have been taken.
+    /// - `31`: Set the interval between noise floor samples, in milliseconds.
+    ///        An interval of 0 stops sampling. Stopped by default. Returns
+    ///        EINVAL if the interval is longer than the alarm can time.
+    /// - `32`: Set the number of most recent samples averaged into the noise
+    ///        floor estimate, between 1 and 16. Defaults to 16.
+    /// - `33`: Set the frame version of transmitted frames: 0 for 802.15.4-2003,
+    ///        1 for 802.15.4-2006, 2 for 802.15.4-2015. Received frames with
+    ///        newer frame versions are dropped. Defaults to 802.15.4-2015.
+    ///        Secured transmissions fail under 802.15.4-2003.
+    /// - `34`: Get the configured frame version, encoded as in command 33.
+    /// - `35`: Get the symbol rate of the radio's PHY mode, in symbols per
+    ///        second. Together with the frequency of the alarm driver, this
+    ///        converts durations in symbols (backoff periods, ACK turnaround,
+    ///        slots) to alarm tics.
+    /// - `36`: Only deliver received frames whose source is the given short
+    ///        address. Frames with a long or absent source address are not
+    ///        delivered while the filter is set. This is applied after, and
+    ///        in addition to, the radio's own destination address matching.
+    /// - `37`: Clear the source address filter, delivering frames from all
+    ///        sources. This is the default.
+    /// - `38`: Enable (`arg1` != 0) or disable (`arg1` == 0) frame aggregation.
+    ///        While enabled, command 26 packs payloads into a shared frame that
+    ///        is sent once it is full or the flush timeout elapses, and
+    ///        received aggregated frames are split back into their payloads.
+    ///        Disabling it sends any buffered payloads. Disabled by default.
+    ///        Sub-frames are kept in buffers the board shares among all apps:
+    ///        command 26 returns ENOMEM if none is free to start a new frame,
+    ///        and received aggregated frames are dropped if none is free to
+    ///        split them. See `aggregate_tx` and `RxClient::receive` for
+    ///        details.
+    /// - `39`: Set the aggregation flush timeout, in milliseconds. Defaults to
+    ///        100 ms. Returns EINVAL if the timeout is longer than the alarm
+    ///        can time.
+    /// - `40`: Only deliver received frames whose payload begins with a
+    ///        pattern. Each payload byte is compared to the corresponding
+    ///        pattern byte in the bits set in the mask.
+    ///        app_cfg (in): n bytes: the pattern +
+    ///                      n bytes: the mask, where n is at most 16. An empty
+    ///                      buffer clears the pattern, matching all frames.
+    ///        Aggregated frames are matched as a whole, before being split.
+    ///        The pattern is kept in a buffer the board provides, shared by all
+    ///        apps. Returns ENOMEM if none is free.
+    /// - `41`: Set the permitted duty cycle, as a percentage of the rolling
+    ///        window spent transmitting, between 1 and 100. 0 stops enforcing
+    ///        the duty cycle, which is the default. While it is enforced,
+    ///        command 26 returns ERESERVE for a transmission whose estimated
+    ///        airtime would exceed the duty cycle. Airtime is measured from the
+    ///        start of a transmission to its completion, so it includes
+    ///        backoffs and waiting for the acknowledgement.
+    /// - `42`: Set the length of the duty cycle window, in milliseconds.
+    ///        Defaults to one hour. Returns EINVAL if the window is shorter
+    ///        than 10 ms or longer than the alarm can time.
+    /// - `43`: Get the time in milliseconds until a transmission with a
+    ///        payload of length `arg1` is permitted by the duty cycle.
+    /// - `44`: Enable (`arg1` != 0) or disable (`arg1` == 0) reporting whether
+    ///        each received frame was addressed to this node, in bit 24 of the
+    ///        destination address argument of the rx callback. Disabled by
+    ///        default.
+    /// - `45`: Get which buffers are currently in place, for debugging buffer
+    ///        leaks. Each bit is set if the buffer is present:
+    ///        bit 0: the kernel transmit buffer, which is absent while a
+    ///               transmission is in flight.
+    ///        bit 1: the radio's receive buffer, which is absent while a
+    ///               received frame is being processed.
+    ///        bits 2-4: this app's read, write and config buffers.
+    ///        bit 5: set if a transmission is in flight.
+    /// - `46`: Compute and check the FCS in software (`arg1` != 0) or in the
+    ///        radio hardware (`arg1` == 0). Received frames with an invalid
+    ///        FCS are dropped either way. Must be followed by command 7 to
+    ///        reconfigure the radio. Defaults to hardware.
+    /// - `47`: Enable (`arg1` != 0) or disable (`arg1` == 0) persistent
+    ///        buffers. While enabled, the read and write buffers stay allowed
+    ///        across operations instead of being given up by each one. Before
+    ///        each transmission, command 48 must set how much of the write
+    ///        buffer to send. Received frames are only copied into the read
+    ///        buffer while an rx callback is registered, i.e. once the app has
+    ///        re-subscribed after handling the previous frame. Disabled by
+    ///        default.
+    /// - `48`: With persistent buffers, set the length of the payload at the
+    ///        start of the write buffer to send with the next transmission.
+    /// - `49`: Get the number of times a unicast frame that was not
+    ///        acknowledged is retransmitted in total, saturating at 255. This
+    ///        combines the retransmissions configured in the radio hardware
+    ///        with those set by command 80, since the radio performs its own
+    ///        for every retransmission by the driver.
+    /// - `50`: Enable (`arg1` != 0) or disable (`arg1` == 0) acting as the PAN
+    ///        coordinator. While enabled, frames without a destination address
+    ///        are accepted by the radio and count as addressed to this node.
+    ///        Must be followed by command 7 to reconfigure the radio. Disabled
+    ///        by default.
+    /// - `51`: Set the minimum gap, in microseconds, after a transmission
+    ///        completes before received frames are delivered again. The radio
+    ///        returns to receiving on its own after transmitting, so frames
+    ///        that finish arriving within the gap, such as ones triggered by
+    ///        the tail of our own transmission, are dropped rather than
+    ///        delivered. The gap is rounded up to the resolution of the alarm.
+    ///        Defaults to 0.
+    /// - `52`: Get the limits this driver was built with. All features are
+    ///        always built in, so only the sizes vary between builds.
+    ///        app_cfg (out): 1 byte: pending transmissions per process,
+    ///                               including queued ones +
+    ///                       1 byte: receive buffers, 2 if the board
+    ///                               configured a spare one +
+    ///                       1 byte: maximum neighbors +
+    ///                       1 byte: maximum keys +
+    ///                       1 byte: maximum noise floor window +
+    ///                       1 byte: maximum transmitted header IEs +
+    ///                       1 byte: maximum transmitted payload IEs +
+    ///                       1 byte: maximum payload pattern length +
+    ///                       1 byte: maximum aggregated payload length +
+    ///                       1 byte: radio buffer size +
+    ///                       1 byte: duty cycle window buckets.
+    /// - `53`: Enable (`arg1` != 0) or disable (`arg1` == 0) counting received
+    ///        frames instead of delivering them. While enabled, no app is
+    ///        woken up or has frames copied into its read buffer; frames only
+    ///        update the counters read by command 54. Noise floor sampling
+    ///        continues as usual. Disabled by default. Enabling it does not
+    ///        reset the counters.
+    /// - `54`: Get a frame counter: the number of frames received (`arg1` ==
+    ///        0), the sum of their lengths excluding the FCS (`arg1` == 1), or
+    ///        the number of distinct source addresses, saturating at 8 (`arg1`
+    ///        == 2). Frames are counted whether or not counting mode is
+    ///        enabled.
+    /// - `55`: Reset the frame counters.
+    /// - `56`: Set how received frames that do not fit into the read buffer
+    ///        are handled: dropped without being delivered (`arg1` == 0),
+    ///        truncated to the read buffer and delivered with bit 25 of the
+    ///        destination address argument of the rx callback set (`arg1` ==
+    ///        1), or truncated as before and also reported on the kernel's
+    ///        debug output (`arg1` == 2). Defaults to 1.
+    /// - `57`: Get the number of times a received frame did not fit into an
+    ///        app's read buffer, across all apps and regardless of how it was
+    ///        handled.
+    /// - `58`: Register (`arg1` != 0) or release (`arg1` == 0) this app as the
+    ///        fallback receiver. In addition to the frames its own filters
+    ///        accept, the fallback receiver is delivered every frame that no
+    ///        other app's filters accepted. Returns EBUSY if another app is
+    ///        already registered; releasing succeeds even if this app was
+    ///        not registered.
+    /// - `59`: Enable (`arg1` != 0) or disable (`arg1` == 0) retransmitting
+    ///        frames that were corrupted by a frame buffer underrun in the
+    ///        radio, up to 3 times. Otherwise, and once the retransmissions
+    ///        are exhausted, the tx callback reports FAIL, which is distinct
+    ///        from a frame that was sent but not acknowledged. Disabled by
+    ///        default.
+    /// - `60`: Get the number of transmissions corrupted by a frame buffer
+    ///        underrun, including retransmissions.
+    /// - `61`: Set the priority of this app's transmissions, between 0 and 255.
+    ///        Whenever the radio becomes free, the pending transmission of the
+    ///        app with the highest priority is sent next, and apps of equal
+    ///        priority take turns. A transmission in flight is never
+    ///        interrupted: a higher priority app only preempts others at frame
+    ///        boundaries. All apps default to priority 0.
+    /// - `62`: Get the result of the radio's most recent clear channel
+    ///        assessment: 1 if the channel was clear, 0 if it was busy. Every
+    ///        transmission performs CCA as part of CSMA-CA, and only reports
+    ///        the channel as busy if channel access failed. Returns FAIL if no
+    ///        CCA has been performed yet.
+    /// - `63`: Get the time in milliseconds since the CCA reported by command
+    ///        62. Returns FAIL if no CCA has been performed yet.
+    /// - `64`: Select the receiver sensitivity mode: high sensitivity (`arg1`
+    ///        == 0), high dynamic range (`arg1` == 1), which ignores weak
+    ///        signals in favor of strong nearby ones, or manual gain (`arg1`
+    ///        == 2). Returns ENOSUPPORT if the radio does not support the
+    ///        mode. Must be followed by command 7 to reconfigure the radio.
+    ///        The RF233 defaults to high sensitivity and does not support
+    ///        manual gain.
+    /// - `65`: Copy the histogram of the signal strengths of received frames
+    ///        into the read buffer, then reset it if `arg1` != 0. The histogram
+    ///        consists of 8 bins, each a 2-byte little-endian count that
+    ///        saturates. Bin 0 counts frames below -86 dBm, bin `i` from 1 to 6
+    ///        counts frames from -94 + 8 * `i` dBm up to 8 dB higher, and bin 7
+    ///        counts frames at -38 dBm and above. The read buffer must be at
+    ///        least 16 bytes long, and stays allowed.
+    /// - `66`: Enable (`arg1` != 0) or disable (`arg1` == 0) listen-only mode.
+    ///        While enabled, the transmit commands 26, 79, 89 and 96 return
+    ///        EUNINSTALLED, which they return for no other reason, and the
+    ///        radio refuses every transmission, including buffered aggregated
+    ///        frames. Must be followed by command 7 so that the radio also
+    ///        stops acknowledging received frames. Disabled by default.
+    /// - `67`: Get which optional features are currently enabled, as a
+    ///        bitmask. The low bits describe the driver as a whole:
+    ///        bit 0: listen-only mode (command 66).
+    ///        bit 1: acting as the PAN coordinator (command 50).
+    ///        bit 2: software FCS (command 46).
+    ///        bit 3: counting received frames only (command 53).
+    ///        bit 4: duty cycle enforcement (command 41).
+    ///        bit 5: noise floor sampling (command 31).
+    ///        bit 6: a receive gap after transmissions (command 51).
+    ///        bit 7: at least one security key is configured (command 24).
+    ///        bit 8: a fallback receiver is registered (command 58).
+    ///        bit 9: promiscuous mode (command 84).
+    ///        bit 10: duplicate suppression (command 91).
+    ///        bit 11: the long source address (command 92).
+    ///        bit 12: a transmit energy budget (command 99).
+    ///        bit 13: received frames are acknowledged (command 104).
+    ///        bit 14: radio events are traced (command 106).
+    ///        bit 15: the receiver is off while idle (command 113).
+    ///        The high bits describe the calling app:
+    ///        bit 16: aggregation (command 38).
+    ///        bit 17: persistent buffers (command 47).
+    ///        bit 18: the length prefix (command 28).
+    ///        bit 19: reporting whether frames were addressed to this node
+    ///                (command 44).
+    ///        bit 20: a source address filter (command 36).
+    ///        bit 21: a payload pattern (command 40).
+    ///        bit 22: a sequence number filter (command 68).
+    ///        bit 23: receiving frames with an invalid FCS (command 83).
+    ///        bit 24: receiving is paused (command 88).
+    ///        bit 25: received frames are held (command 101).
+    ///        bit 26: a transmit payload offset (command 105).
+    ///        bit 27: a minimum interval between rx callbacks (command 112).
+    /// - `68`: Only deliver received frames whose sequence number lies between
+    ///        the low byte of `arg1` and the next byte of `arg1`, inclusive. If
+    ///        the low byte is larger, the range wraps around past 255. Frames
+    ///        with a suppressed sequence number are not delivered while the
+    ///        filter is set.
+    /// - `69`: Clear the sequence number filter, delivering frames with any
+    ///        sequence number. This is the default.
+    /// - `70`: Adapt the permitted payload size to the link quality. While the
+    ///        link is marginal, command 26 returns ESIZE for payloads longer
+    ///        than `arg1` bytes, and command 73 reports the current limit. The
+    ///        link is marginal while the moving average of the signal strength
+    ///        of received frames is below the threshold set by command 71, or
+    ///        once as many consecutive transmissions as set by command 72 were
+    ///        not acknowledged. The link recovers as stronger frames arrive or
+    ///        a transmission is acknowledged. An `arg1` of 0 stops adapting the
+    ///        payload size, which is the default.
+    /// - `71`: Set the average signal strength in dBm below which the link is
+    ///        marginal, cast from signed the same way as the transmission
+    ///        power. Defaults to -85 dBm.
+    /// - `72`: Set the number of consecutive unacknowledged transmissions
+    ///        after which the link is marginal. Defaults to 3.
+    /// - `73`: Get the largest payload that command 26 currently permits,
+    ///        assuming the smallest possible MAC header.
+    /// - `74`: Get the number of frames received with an invalid FCS on
+    ///        channel `arg1`, between 11 and 26, since the counts were last
+    ///        reset. Frames count against the channel the radio was
+    ///        configured with when they arrived, so counts are only
+    ///        meaningful for channels the radio has actually been on.
+    /// - `75`: Reset the counts of frames received with an invalid FCS on
+    ///        every channel.
+    /// - `76`: Get the signal quality of the last frame copied into this app's
+    ///        read buffer, unaffected by frames received for other apps since.
+    ///        The low byte holds its signal strength in dBm, cast to unsigned
+    ///        the same way as the transmission power, and the next byte holds
+    ///        its link quality indication, which is 255 if the radio could not
+    ///        determine it. Returns FAIL if no frame has been delivered yet.
+    ///        The receive callback arguments already carry the addresses, so
+    ///        apps query this from their receive callback instead.
+    /// - `77`: Turn the radio off, putting it to sleep. Transmissions return
+    ///        EOFF while the radio is off. Returns EBUSY while a transmission
+    ///        is in flight and EALREADY if the radio is already off.
+    /// - `78`: Turn the radio back on. Returns EALREADY if it is already on.
+    /// - `79`: Transmit a frame to the given long address, the same way as
+    ///        command 26. Bit 25 of `arg1` works as for command 26.
+    ///        app_cfg (in): 11 bytes: the security configuration, as for
+    ///                      command 26 +
+    ///                      8 bytes: the long address.
+    /// - `80`: Set the number of times the driver retransmits a unicast frame
+    ///        that was not acknowledged, between 0 and 255, on top of the
+    ///        retransmissions the radio performs itself. Command 49 reports
+    ///        the resulting total. Only once every retry failed is the
+    ///        transmit callback invoked, with the number of retries used as
+    ///        its third argument. Disabled (0) by default.
+    /// - `81`: Start a clear channel assessment on the current channel. The
+    ///        radio measures the energy on the channel, which is busy if the
+    ///        measured signal strength is at or above the threshold set by
+    ///        command 82, and the result is passed to the callback registered
+    ///        with subscribe 3. Unlike command 62, this does not reflect
+    ///        carrier sense. Returns EBUSY while a transmission is in flight
+    ///        or another assessment or measurement is in progress.
+    /// - `82`: Set the clear channel assessment threshold in dBm, cast from
+    ///        signed the same way as the transmission power. Defaults to -77
+    ///        dBm, the radio's own energy detection threshold.
+    /// - `83`: Enable (`arg1` != 0) or disable (`arg1` == 0) receiving frames
+    ///        whose FCS is invalid, for diagnostics. Such frames are dropped
+    ///        by default. When delivered, bit 26 of the encoded destination
+    ///        address is set. Frames with an invalid FCS are never counted by
+    ///        command 54.
+    /// - `84`: Enable (`arg1` != 0) or disable (`arg1` == 0) promiscuous mode.
+    ///        While enabled, the radio receives every frame on the channel,
+    ///        including frames addressed to other nodes or PANs, and stops
+    ///        acknowledging frames so that it never acknowledges foreign
+    ///        traffic. Disabling it restores filtering by the configured
+    ///        addresses and PAN ID. Enabled by default. Must be followed by
+    ///        command 7.
+    /// - `85`: Enable (`arg1` != 0) or disable (`arg1` == 0) incrementing the
+    ///        data sequence number after each transmission. While disabled,
+    ///        every frame carries the value set with command 27. Enabled by
+    ///        default.
+    /// - `86`: Get the data sequence number of the most recently completed
+    ///        transmission. Returns FAIL if no frame has been transmitted yet.
+    /// - `87`: Run the loopback self-test: the radio is put into loopback mode
+    ///        and a known frame is transmitted to our own address, which the
+    ///        driver checks is received. The outcome is passed to the callback
+    ///        registered with subscribe 4. Returns ENOSUPPORT if the radio has
+    ///        no loopback mode, EOFF if the radio is off, and EBUSY while a
+    ///        transmission or another self-test is in progress.
+    /// - `88`: Pause (`arg1` == 0) or resume (`arg1` != 0) receiving for the
+    ///        calling app. While paused, received frames are dropped for this
+    ///        app without touching `app_read` or scheduling the rx callback,
+    ///        but the buffer and callback stay registered. The radio keeps
+    ///        receiving for other apps.
+    /// - `89`: Transmit a frame without payload and without security to the
+    ///        given short address, for example as a keepalive. The frame only
+    ///        consists of the MAC header and FCS, and `app_write` is not used.
+    ///        It is queued and completes like a frame sent with command 26,
+    ///        and also returns EUNINSTALLED in listen-only mode (command 66).
+    /// - `90`: Get the features the radio supports, as a bitmask:
+    ///        bit 0: filtering on the long address (command 3).
+    ///        bit 1: acknowledging received frames in hardware.
+    ///        bit 2: retransmitting unacknowledged frames in hardware
+    ///               (command 49).
+    ///        bit 3: the loopback self-test (command 87).
+    ///        bit 4: adjustable transmission power (command 6).
+    ///        bit 5: promiscuous mode (command 84).
+    ///        bit 6: sleeping while off (commands 77 and 78).
+    /// - `91`: Enable (`arg1` != 0) or disable (`arg1` == 0) dropping received
+    ///        frames with the same source address and sequence number as one
+    ///        of the last 8 frames received, such as retransmissions whose
+    ///        acknowledgement was lost or frames relayed along several paths.
+    ///        Frames without a source address or sequence number are always
+    ///        delivered. Disabling it forgets the remembered frames. Disabled
+    ///        by default.
+    /// - `92`: Use the long (`arg1` != 0) or short (`arg1` == 0) address as
+    ///        the source address of transmitted frames, setting the source
+    ///        addressing mode in the frame control field to match. A PAN
+    ///        coordinator (command 50) usually identifies itself by its long
+    ///        address. Returns EBUSY while a transmission is in flight. Short
+    ///        by default.
+    /// - `93`: Get the driver's statistics, each a 4-byte little-endian count
+    ///        that wraps around. They are kept since boot and never reset.
+    ///        app_cfg (out): 4 bytes: frames handed to the radio +
+    ///                       4 bytes: successful transmissions, acknowledged
+    ///                                unless broadcast +
+    ///                       4 bytes: failed or unacknowledged transmissions,
+    ///                                including ones that could not start +
+    ///                       4 bytes: frames received with a valid FCS +
+    ///                       4 bytes: frames received with an invalid FCS, on
+    ///                                all channels (see command 74) +
+    ///                       4 bytes: frames not delivered to an app because
+    ///                                it had no read buffer.
+    /// - `94`: Set the short MAC address to the low 16 bits of `arg1` and the
+    ///        PAN ID to the next 16 bits, then commit the configuration like
+    ///        command 7. Both change at once, so frames are never sent or
+    ///        filtered with the new address but the old PAN ID. Returns EBUSY
+    ///        while a transmission is in flight.
+    /// - `95`: Get the radio's factory-programmed EUI-64, for example to derive
+    ///        a stable long address or IPv6 interface identifier. Returns
+    ///        ENOSUPPORT if the radio has none, which is the case for the
+    ///        RF233, and ESIZE if the buffer is shorter than 8 bytes.
+    ///        app_cfg (out): 8 bytes: the EUI-64.
+    /// - `96`: Transmit the contents of the write buffer verbatim as a complete
+    ///        frame, including its MAC header but not the FCS, which the radio
+    ///        appends unless command 115 is enabled. The driver neither checks
+    ///        nor fills in any header field, applies no security, and does not
+    ///        use the data sequence number, so malformed or misaddressed
+    ///        frames are sent as they are. The radio still performs CSMA-CA
+    ///        and waits for an acknowledgement if the frame requests one. Only
+    ///        meant for protocol experiments. Returns EBUSY unless the driver
+    ///        is idle and this app has no pending transmission, EINVAL if the
+    ///        frame is too short or long or has a reserved frame type, and
+    ///        EUNINSTALLED in listen-only mode (command 66). Completes through
+    ///        the transmit callback.
+    /// - `97`: Set the CSMA-CA parameters: the minimum backoff exponent in the
+    ///        low byte of `arg1`, the maximum backoff exponent in the next
+    ///        byte, and the number of retries after a busy channel before
+    ///        channel access fails in the byte after that. Smaller values
+    ///        lower the latency of transmissions at the cost of more
+    ///        collisions. Returns EINVAL unless the minimum exponent does not
+    ///        exceed the maximum, which is at most 8, and there are at most 5
+    ///        retries. Must be followed by command 7. The RF233 defaults to
+    ///        exponents 3 and 5 and no retries.
+    /// - `98`: Cancel this app's transmissions: the frame in flight, unless
+    ///        the radio has already sent it, the pending one, the queued ones
+    ///        and any buffered sub-frames. The transmit callback is invoked
+    ///        with ECANCEL unless a frame that could not be stopped is still in
+    ///        flight, in which case it completes as usual. Returns EBUSY if
+    ///        only such a frame is left and EALREADY if there is nothing to
+    ///        cancel.
+    /// - `99`: Set a transmit energy budget of `arg1` microjoules, replacing
+    ///        what was left of any previous budget, or remove the budget if
+    ///        `arg1` is 0, which is the default. Each transmission consumes
+    ///        the energy estimated from its measured airtime, like the duty
+    ///        cycle, and the configured transmission power. Commands 26, 89
+    ///        and 96 return ERESERVE for a transmission whose estimated energy
+    ///        exceeds what is left, until the budget is set again.
+    /// - `100`: Get the remaining transmit energy budget in microjoules.
+    ///         Returns FAIL if there is no budget.
+    /// - `101`: Enable (`arg1` != 0) or disable (`arg1` == 0) holding received
+    ///         frames. While enabled, received frames are not delivered to the
+    ///         rx callback. Instead, up to 2 of them are kept in the kernel
+    ///         until fetched with command 102. They are held in buffers that
+    ///         the board shares among all apps. Frames that arrive while every
+    ///         slot of the app or every buffer is taken are dropped. Disabling
+    ///         it discards held frames.
+    /// - `102`: Copy the oldest held frame into the read buffer, in the
+    ///         format described in `RxClient::receive`, and release its
+    ///         buffer. Returns the number of bytes copied, FAIL if no frame is
+    ///         held, EINVAL if no read buffer is allowed, and ESIZE if the read
+    ///         buffer is too small. The frame stays held on error.
+    /// - `103`: Get why the most recent transmission failed: 1 if the channel
+    ///         was busy, 2 if the frame was not acknowledged, 3 if the radio
+    ///         failed while sending it, and 4 for any other failure. Returns 0
+    ///         if it succeeded. This is cleared whenever a transmission
+    ///         starts.
+    /// - `104`: Enable (`arg1` != 0) or disable (`arg1` == 0) acknowledging
+    ///         received frames that are addressed to this node and request an
+    ///         acknowledgement. Enabled by default. Listen-only and
+    ///         promiscuous mode, which is also enabled by default (command
+    ///         84), always disable it. This only concerns frames sent by
+    ///         other nodes: whether our own transmissions are acknowledged,
+    ///         and retried (command 80), depends on the receiver. While disabled, other nodes retry every frame they
+    ///         send to this node and report it as not acknowledged. Must be
+    ///         followed by command 7.
+    /// - `105`: Set the number of bytes at the start of the write buffer that
+    ///         precede the payload, which are not transmitted. This lets an
+    ///         app keep its own data in front of the payload, or build its own
+    ///         headers behind reserved space, without moving bytes around.
+    ///         With persistent buffers, the length set by command 48 counts
+    ///         from this offset. The offset also applies to the raw frames of
+    ///         command 96. Defaults to 0. Returns ESIZE if the offset exceeds
+    ///         the write buffer that is currently allowed, and EBUSY while the
+    ///         app has a transmission pending.
+    /// - `106`: Enable (`arg1` != 0) or disable (`arg1` == 0) tracing radio
+    ///         events. While enabled, the driver records the last 16 commands,
+    ///         transmissions, completions, receptions and dropped frames in a
+    ///         ring, which command 107 copies out. Enabling it clears the
+    ///         ring. Disabled by default.
+    /// - `107`: Copy the traced radio events into the config buffer, oldest
+    ///         first, and return how many there are. Unused entries are
+    ///         zeroed.
+    ///         app_cfg (out): 128 bytes: 16 entries of 8 bytes each: the time
+    ///                        of the event in milliseconds (4 bytes, LE), the
+    ///                        event (1 byte), its detail (1 byte) and its
+    ///                        argument (2 bytes, LE). See `TraceEvent` for
+    ///                        the events and what they carry.
+    /// - `108`: Get a snapshot of the radio configuration. Returns ESIZE if the
+    ///         buffer is shorter than 16 bytes.
+    ///         app_cfg (out): 16 bytes: the channel, the transmission power in
+    ///                        dBm, the short address (2 bytes, LE), the PAN ID
+    ///                        (2 bytes, LE), the source addressing mode (0 for
+    ///                        short, 1 for long, see command 92), whether the
+    ///                        radio is on, and the long address (8 bytes).
+    /// - `109`: Start an energy detection scan, which measures the signal
+    ///         strength on each channel from 11 to 26 in turn and then
+    ///         returns to the current channel. Transmissions wait until it is
+    ///         done, and the channel cannot be changed meanwhile. Apps
+    ///         subscribed to channel changes are not notified of the channels
+    ///         visited. The callback set up with subscribe 6 fires once the
+    ///         scan completes. Returns EBUSY while a transmission, clear
+    ///         channel assessment or another scan is in progress, and EOFF if
+    ///         the radio is off.
+    /// - `110`: Copy the signal strengths measured by the last energy
+    ///         detection scan.
+    ///         app_cfg (out): 16 bytes: the signal strength on each channel
+    ///                        from 11 to 26 in dBm, cast to unsigned like the
+    ///                        transmission power.
+    /// - `111`: Reset this app's state in the driver, for instance to recover
+    ///         after the app restarts. All of its callbacks and buffers are
+    ///         released, transmissions that have not been handed to the radio
+    ///         yet are dropped without a callback, and every per-app setting,
+    ///         such as filters, aggregation and held frames, returns to its
+    ///         default. Its frame in flight is aborted unless the radio has
+    ///         already sent it. A clear channel assessment, scan, self-test or
+    ///         transmission that cannot be stopped and is already under way
+    ///         still completes, but no longer invokes a callback. The radio
+    ///         configuration is shared by all apps, so it is left as it is.
+    /// - `112`: Set the minimum interval between two rx callbacks to the low
+    ///         24 bits of `arg1`, in milliseconds. Frames that arrive sooner
+    ///         after the previous rx callback are dropped, or if bit 24 of
+    ///         `arg1` is set, held as with command 101 until the app fetches
+    ///         them with command 102 or the slots run out. Either way, the
+    ///         next rx callback reports how many frames were suppressed. An
+    ///         interval of 0 invokes the rx callback for every frame, which is
+    ///         the default.
+    /// - `113`: Keep the receiver off while the radio is idle (`arg1` != 0),
+    ///         or on (`arg1` == 0), which is the default. While it is off, the
+    ///         radio only listens for the acknowledgements of its own
+    ///         transmissions, and no frames are received, for duty-cycled
+    ///         protocols that turn the receiver on only to poll. Unlike
+    ///         command 77, the radio stays ready to transmit. Must be followed
+    ///         by command 7.
+    /// - `114`: Get what the radio is currently doing: 0 if it is asleep
+    ///         (command 77), 1 if it is idle with its receiver off (command
+    ///         113), 2 if its receiver is on, and 3 while it is transmitting.
+    /// - `115`: Let the frames sent by command 96 end in their own FCS
+    ///         (`arg1` != 0), which is sent as it is, even if it is invalid.
+    ///         The last two bytes of each frame are the FCS, so they count
+    ///         towards its length. While enabled, the radio no longer appends
+    ///         the FCS, so it is computed in software for all other frames, as
+    ///         with command 46. Must be followed by command 7. Disabled by
+    ///         default.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        self.trace(TraceEvent::Command, 0, command_num as u16);
+        match command_num {
+            0 => {
+                self.mac.get_chip_id().map_or(ReturnCode::SUCCESS, |id| {
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: id as usize + 1 }
+                })
+            }
+            1 => {
+                if self.mac.is_on() {
+                    ReturnCode::SUCCESS
+                } else {
+                    ReturnCode::EOFF
+                }
+            }
+            2 => {
+                self.mac.set_address(arg1 as u16);
+                self.config_changed.set(self.config_changed.get() | CONFIG_SHORT_ADDRESS);
+                ReturnCode::SUCCESS
+            }
+            3 => {
+                self.do_with_app(appid, |app| {
+                    app.app_cfg.take().as_ref().map_or(ReturnCode::EINVAL, |cfg| {
+                        if cfg.len() != 8 {
+                            return ReturnCode::ESIZE;
+                        }
+                        let mut addr_long = [0u8; 8];
+                        addr_long.copy_from_slice(cfg.as_ref());
+                        self.mac.set_address_long(addr_long);
+                        self.config_changed.set(self.config_changed.get() | CONFIG_LONG_ADDRESS);
+                        ReturnCode::SUCCESS
+                    })
+                })
+            }
+            4 => {
+                self.mac.set_pan(arg1 as u16);
+                self.config_changed.set(self.config_changed.get() | CONFIG_PAN);
+                ReturnCode::SUCCESS
+            }
+            5 => {
+                if arg1 > u8::max_value() as usize {
+                    return ReturnCode::EINVAL;
+                }
+                if self.current_app.get().is_some() || self.scan_app.get().is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let channel = arg1 as u8;
+                let changed = channel != self.mac.get_channel();
+                let result = self.mac.set_channel(channel);
+                if result == ReturnCode::SUCCESS {
+                    self.config_changed.set(self.config_changed.get() | CONFIG_CHANNEL);
+                    if changed {
+                        self.notify_channel_changed(channel);
+                    }
+                }
+                result
+            }
+            6 => {
+                // Userspace casts the i8 to a u8 before casting to u32, so this works.
+                let result = self.mac.set_tx_power(arg1 as i8);
+                if result == ReturnCode::SUCCESS {
+                    self.config_changed.set(self.config_changed.get() | CONFIG_TX_POWER);
+                }
+                result
+            }
+            7 => {
+                self.config_commit();
+                ReturnCode::SUCCESS
+            }
+            8 => {
+                // Guarantee that address is positive by adding 1
+                let addr = self.mac.get_address();
+                ReturnCode::SuccessWithValue { value: (addr as usize) + 1 }
+            }
+            9 => {
+                self.do_with_cfg_mut(appid, 8, |cfg| {
+                    cfg.copy_from_slice(&self.mac.get_address_long());
+                    ReturnCode::SUCCESS
+                })
+            }
+            10 => {
+                // Guarantee that the PAN is positive by adding 1
+                let pan = self.mac.get_pan();
+                ReturnCode::SuccessWithValue { value: (pan as usize) + 1 }
+            }
+            11 => {
+                // Guarantee that the channel is positive by adding 1
+                let channel = self.mac.get_channel();
+                ReturnCode::SuccessWithValue { value: (channel as usize) + 1 }
+            }
+            12 => {
+                // Cast the power to unsigned, then ensure it is positive
+                let power = self.mac.get_tx_power() as u8;
+                ReturnCode::SuccessWithValue { value: (power as usize) + 1 }
+            }
+            13 => {
+                // Guarantee that it is positive by adding 1
+                ReturnCode::SuccessWithValue { value: MAX_NEIGHBORS + 1 }
+            }
+            14 => {
+                // Guarantee that it is positive by adding 1
+                ReturnCode::SuccessWithValue { value: self.num_neighbors.get() + 1 }
+            }
+            15 => {
+                self.get_neighbor(arg1)
+                    .map_or(ReturnCode::EINVAL, |neighbor| {
+                        ReturnCode::SuccessWithValue { value: (neighbor.short_addr as usize) + 1 }
+                    })
+            }
+            16 => {
+                self.do_with_cfg_mut(appid, 8, |cfg| {
+                    self.get_neighbor(arg1)
+                        .map_or(ReturnCode::EINVAL, |neighbor| {
+                            cfg.copy_from_slice(&neighbor.long_addr);
+                            ReturnCode::SUCCESS
+                        })
+                })
+            }
+            17 => {
+                self.do_with_cfg(appid, 8, |cfg| {
+                    let mut new_neighbor: DeviceDescriptor = Default::default();
+                    new_neighbor.short_addr = arg1 as u16;
+                    new_neighbor.long_addr.copy_from_slice(cfg);
+                    self.add_neighbor(new_neighbor)
+                        .map_or(ReturnCode::EINVAL,
+                                |index| ReturnCode::SuccessWithValue { value: index + 1 })
+                })
+            }
+            18 => self.remove_neighbor(arg1),
+            19 => {
+                // Guarantee that it is positive by adding 1
+                ReturnCode::SuccessWithValue { value: MAX_KEYS + 1 }
+            }
+            20 => {
+                // Guarantee that it is positive by adding 1
+                ReturnCode::SuccessWithValue { value: self.num_keys.get() + 1 }
+            }
+            21 => {
+                self.get_key(arg1)
+                    .map_or(ReturnCode::EINVAL,
+                            |key| ReturnCode::SuccessWithValue { value: (key.level as usize) + 1 })
+            }
+            22 => {
+                self.do_with_cfg_mut(appid, 10, |cfg| {
+                    self.get_key(arg1)
+                        .and_then(|key| encode_key_id(&key.key_id, cfg).done())
+                        .map_or(ReturnCode::EINVAL, |_| ReturnCode::SUCCESS)
+                })
+            }
+            23 => {
+                self.do_with_cfg_mut(appid, 16, |cfg| {
+                    self.get_key(arg1)
+                        .map_or(ReturnCode::EINVAL, |key| {
+                            cfg.copy_from_slice(&key.key);
+                            ReturnCode::SUCCESS
+                        })
+                })
+            }
+            24 => {
+                self.do_with_cfg(appid, 27, |cfg| {
+                    KeyDescriptor::decode(cfg)
+                        .done()
+                        .and_then(|(_, new_key)| self.add_key(new_key))
+                        .map(|index| ReturnCode::SuccessWithValue { value: index + 1 })
+                        .unwrap_or(ReturnCode::EINVAL)
+                })
+            }
+            25 => self.remove_key(arg1),
+            26 | 79 => {
+                if self.mac.is_listen_only() {
+                    return ReturnCode::EUNINSTALLED;
+                }
+                self.do_with_app(appid, |app| {
+                    if app.pending_tx.is_some() &&
+                       (app.aggregate || app.tx_queue_len == TX_QUEUE_LEN) {
+                        // Cannot queue any more transmissions for this process.
+                        return ReturnCode::EBUSY;
+                    }
+                    let long = command_num == 79 || arg1 & TX_LONG_ADDRESS != 0;
+                    let no_ack = arg1 & TX_NO_ACK != 0;
+                    if no_ack && app.aggregate {
+                        // Aggregated payloads share one frame and its settings
+                        return ReturnCode::EINVAL;
+                    }
+                    let next_tx = app.app_cfg.as_ref().and_then(|cfg| {
+                        let dst_addr = if !long {
+                            if cfg.len() != 11 {
+                                return None;
+                            }
+                            MacAddress::Short(arg1 as u16)
+                        } else {
+                            if cfg.len() != 19 {
+                                return None;
+                            }
+                            let mut addr_long = [0u8; 8];
+                            addr_long.copy_from_slice(&cfg.as_ref()[11..]);
+                            MacAddress::Long(addr_long)
+                        };
+                        let level = match SecurityLevel::from_scf(cfg.as_ref()[0]) {
+                            Some(level) => level,
+                            None => {
+                                return None;
+                            }
+                        };
+                        if level == SecurityLevel::None {
+                            Some((dst_addr, None))
+                        } else {
+                            let key_id = match decode_key_id(&cfg.as_ref()[1..]).done() {
+                                Some((_, key_id)) => key_id,
+                                None => {
+                                    return None;
+                                }
+                            };
+                            Some((dst_addr, Some((level, key_id))))
+                        }
+                    });
+                    if next_tx.is_none() {
+                        return ReturnCode::EINVAL;
+                    }
+                    let payload_len = app.tx_payload_len().unwrap_or(0);
+                    if payload_len > self.payload_limit() {
+                        return ReturnCode::ESIZE;
+                    }
+                    if !self.tx_permitted(Self::estimate_airtime(payload_len)) {
+                        return ReturnCode::ERESERVE;
+                    }
+                    if app.aggregate {
+                        return match next_tx {
+                            Some(next_tx) => self.aggregate_tx(appid, app, next_tx),
+                            None => ReturnCode::EINVAL,
+                        };
+                    }
+                    if app.pending_tx.is_some() {
+                        return match next_tx {
+                            Some(next_tx) => self.enqueue_tx(appid, app, next_tx, no_ack),
+                            None => ReturnCode::EINVAL,
+                        };
+                    }
+                    app.pending_tx = next_tx;
+                    app.pending_no_ack = no_ack;
+
+                    self.do_next_tx_sync(appid)
+                })
+            }
+            27 => {
+                self.mac.set_data_sequence(arg1 as u8);
+                ReturnCode::SUCCESS
+            }
+            28 => {
+                self.do_with_app(appid, |app| {
+                    app.rx_length_prefix = arg1 != 0;
+                    ReturnCode::SUCCESS
+                })
+            }
+            29 => {
+                if self.current_app.get().is_none() {
+                    return ReturnCode::EALREADY;
+                }
+                let elapsed = self.alarm.now().wrapping_sub(self.tx_started.get());
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: (Self::tics_to_ms(elapsed) as usize) + 1 }
+            }
+            30 => {
+                self.get_noise_floor().map_or(ReturnCode::FAIL, |noise_floor| {
+                    // Cast the estimate to unsigned, then ensure it is positive
+                    ReturnCode::SuccessWithValue { value: (noise_floor as u8 as usize) + 1 }
+                })
+            }
+            31 => {
+                if !Self::ms_in_range(arg1) {
+                    return ReturnCode::EINVAL;
+                }
+                self.set_noise_interval(arg1 as u32);
+                ReturnCode::SUCCESS
+            }
+            32 => {
+                if arg1 == 0 || arg1 > MAX_NOISE_WINDOW {
+                    return ReturnCode::EINVAL;
+                }
+                self.noise_window.set(arg1);
+                ReturnCode::SUCCESS
+            }
+            33 => {
+                let version = match arg1 {
+                    0 => FrameVersion::V2003,
+                    1 => FrameVersion::V2006,
+                    2 => FrameVersion::V2015,
+                    _ => {
+                        return ReturnCode::EINVAL;
+                    }
+                };
+                self.mac.set_frame_version(version);
                 ReturnCode::SUCCESS
-            })
-            .unwrap_or(ReturnCode::SUCCESS)
-    }
-}
-
-impl<'a> mac::DeviceProcedure for RadioDriver<'a> {
-    /// Gets the long address corresponding to the neighbor that matches the given
-    /// MAC address. If no such neighbor exists, returns `None`.
-    fn lookup_addr_long(&self, addr: MacAddress) -> Option<([u8; 8])> {
-        self.neighbors.and_then(|neighbors| {
-            neighbors[..self.num_neighbors.get()]
-                .iter()
-                .find(|neighbor| match addr {
-                    MacAddress::Short(addr) => addr == neighbor.short_addr,
-                    MacAddress::Long(addr) => addr == neighbor.long_addr,
+            }
+            34 => {
+                let version = (self.mac.get_frame_version() as u16 >> 12) as usize;
+                // Guarantee that the version is positive by adding 1
+                ReturnCode::SuccessWithValue { value: version + 1 }
+            }
+            35 => {
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: (self.mac.get_symbol_rate() as usize) + 1 }
+            }
+            36 => {
+                self.do_with_app(appid, |app| {
+                    app.src_filter = Some(arg1 as u16);
+                    ReturnCode::SUCCESS
                 })
-                .map(|neighbor| neighbor.long_addr)
-        })
-    }
-}
-
-impl<'a> mac::KeyProcedure for RadioDriver<'a> {
-    /// Gets the key corresponding to the key that matches the given security
-    /// level `level` and key ID `key_id`. If no such key matches, returns
-    /// `None`.
-    fn lookup_key(&self, level: SecurityLevel, key_id: KeyId) -> Option<([u8; 16])> {
-        self.keys.and_then(|keys| {
-            keys[..self.num_keys.get()]
-                .iter()
-                .find(|key| key.level == level && key.key_id == key_id)
-                .map(|key| key.key)
-        })
-    }
-}
-
-impl<'a> Driver for RadioDriver<'a> {
-    /// Setup buffers to read/write from.
-    ///
-    /// ### `allow_num`
-    ///
-    /// - `0`: Read buffer. Will contain the received frame.
-    /// - `1`: Write buffer. Contains the frame payload to be transmitted.
-    /// - `2`: Config buffer. Used to contain miscellaneous data associated with
-    ///        some commands because the system call parameters / return codes are
-    ///        not enough to convey the desired information.
-    fn allow(&self, appid: AppId, allow_num: usize, slice: AppSlice<Shared, u8>) -> ReturnCode {
-        match allow_num {
-            0 | 1 | 2 => {
+            }
+            37 => {
                 self.do_with_app(appid, |app| {
-                    match allow_num {
-                        0 => app.app_read = Some(slice),
-                        1 => app.app_write = Some(slice),
-                        2 => app.app_cfg = Some(slice),
-                        _ => {}
+                    app.src_filter = None;
+                    ReturnCode::SUCCESS
+                })
+            }
+            38 => {
+                self.do_with_app(appid, |app| {
+                    app.aggregate = arg1 != 0;
+                    if !app.aggregate {
+                        Self::flush_aggregate(app);
+                    }
+                    self.do_next_tx_sync(appid)
+                })
+            }
+            39 => {
+                if !Self::ms_in_range(arg1) {
+                    return ReturnCode::EINVAL;
+                }
+                self.agg_timeout.set(arg1 as u32);
+                self.arm_aggregate_timer();
+                ReturnCode::SUCCESS
+            }
+            40 => {
+                self.do_with_app(appid, |app| {
+                    let cfg = match app.app_cfg.take() {
+                        Some(cfg) => cfg,
+                        None => {
+                            return ReturnCode::EINVAL;
+                        }
+                    };
+                    let len = cfg.len() / 2;
+                    if cfg.len() % 2 != 0 || len > MAX_PAYLOAD_PATTERN {
+                        return ReturnCode::EINVAL;
+                    }
+                    if len == 0 {
+                        app.pattern.take().map(|(index, _)| self.pattern_pool.release(index));
+                        return ReturnCode::SUCCESS;
                     }
+                    let held = app.pattern.map(|(index, _)| index);
+                    let index = match held.or_else(|| self.pattern_pool.claim(appid)) {
+                        Some(index) => index,
+                        None => {
+                            return ReturnCode::ENOMEM;
+                        }
+                    };
+                    self.pattern_pool.map(index, |buf| {
+                        let (pattern, mask) = buf.split_at_mut(MAX_PAYLOAD_PATTERN);
+                        pattern[..len].copy_from_slice(&cfg.as_ref()[..len]);
+                        mask[..len].copy_from_slice(&cfg.as_ref()[len..]);
+                    });
+                    app.pattern = Some((index, len));
                     ReturnCode::SUCCESS
                 })
             }
-            _ => ReturnCode::ENOSUPPORT,
-        }
-    }
-
-    /// Setup callbacks.
-    ///
-    /// ### `subscribe_num`
-    ///
-    /// - `0`: Setup callback for when frame is received.
-    /// - `1`: Setup callback for when frame is transmitted.
-    fn subscribe(&self, subscribe_num: usize, callback: Callback) -> ReturnCode {
-        match subscribe_num {
-            0 => {
-                self.do_with_app(callback.app_id(), |app| {
-                    app.rx_callback = Some(callback);
+            41 => {
+                if arg1 > 100 {
+                    return ReturnCode::EINVAL;
+                }
+                self.duty_cycle.set(arg1 as u32);
+                ReturnCode::SUCCESS
+            }
+            42 => {
+                if arg1 < DUTY_CYCLE_BUCKETS || !Self::ms_in_range(arg1) {
+                    return ReturnCode::EINVAL;
+                }
+                // Restart accounting, since the buckets change length
+                self.duty_cycle_window.set(arg1 as u32);
+                self.airtime_buckets.set([0; DUTY_CYCLE_BUCKETS]);
+                self.airtime_bucket_start.set(self.alarm.now());
+                ReturnCode::SUCCESS
+            }
+            43 => {
+                let wait = self.duty_cycle_wait(Self::estimate_airtime(arg1)).unwrap_or(0);
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: (wait as usize) + 1 }
+            }
+            44 => {
+                self.do_with_app(appid, |app| {
+                    app.check_dst = arg1 != 0;
                     ReturnCode::SUCCESS
                 })
             }
-            1 => {
-                self.do_with_app(callback.app_id(), |app| {
-                    app.tx_callback = Some(callback);
+            45 => {
+                let mut buffers = 0;
+                if self.kernel_tx.is_some() {
+                    buffers |= 1 << 0;
+                }
+                if self.mac.has_receive_buffer() {
+                    buffers |= 1 << 1;
+                }
+                if self.current_app.get().is_some() {
+                    buffers |= 1 << 5;
+                }
+                self.do_with_app(appid, |app| {
+                    if app.app_read.is_some() {
+                        buffers |= 1 << 2;
+                    }
+                    if app.app_write.is_some() {
+                        buffers |= 1 << 3;
+                    }
+                    if app.app_cfg.is_some() {
+                        buffers |= 1 << 4;
+                    }
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: buffers + 1 }
+                })
+            }
+            46 => {
+                self.mac.set_software_crc(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
+            47 => {
+                self.do_with_app(appid, |app| {
+                    app.persistent_buffers = arg1 != 0;
+                    app.tx_len = None;
                     ReturnCode::SUCCESS
                 })
             }
-            _ => ReturnCode::ENOSUPPORT,
-        }
-    }
-
-    /// IEEE 802.15.4 MAC device control.
-    ///
-    /// For some of the below commands, one 32-bit argument is not enough to
-    /// contain the desired input parameters or output data. For those commands,
-    /// the config slice `app_cfg` is used as a channel to shuffle information
-    /// between kernel space and user space. The expected size of the slice
-    /// varies by command, and acts essentially like a custom FFI. That is, the
-    /// userspace library MUST `allow()` a buffer of the correct size, otherwise
-    /// the call is EINVAL. When used, the expected format is described below.
-    ///
-    /// ### `command_num`
-    ///
-    /// - `0`: Driver check.
-    /// - `1`: Return radio status. SUCCESS/EOFF = on/off.
-    /// - `2`: Set short MAC address.
-    /// - `3`: Set long MAC address.
-    ///        app_cfg (in): 8 bytes: the long MAC address.
-    /// - `4`: Set PAN ID.
-    /// - `5`: Set channel.
-    /// - `6`: Set transmission power.
-    /// - `7`: Commit any configuration changes.
-    /// - `8`: Get the short MAC address.
-    /// - `9`: Get the long MAC address.
-    ///        app_cfg (out): 8 bytes: the long MAC address.
-    /// - `10`: Get the PAN ID.
-    /// - `11`: Get the channel.
-    /// - `12`: Get the transmission power.
-    /// - `13`: Get the maximum number of neighbors.
-    /// - `14`: Get the current number of neighbors.
-    /// - `15`: Get the short address of the neighbor at an index.
-    /// - `16`: Get the long address of the neighbor at an index.
-    ///        app_cfg (out): 8 bytes: the long MAC address.
-    /// - `17`: Add a new neighbor with the given short and long address.
-    ///        app_cfg (in): 8 bytes: the long MAC address.
-    /// - `18`: Remove the neighbor at an index.
-    /// - `19`: Get the maximum number of keys.
-    /// - `20`: Get the current number of keys.
-    /// - `21`: Get the security level of the key at an index.
-    /// - `22`: Get the key id of the key at an index.
-    ///        app_cfg (out): 1 byte: the key ID mode +
-    ///                       up to 9 bytes: the key ID.
-    /// - `23`: Get the key at an index.
-    ///        app_cfg (out): 16 bytes: the key.
-    /// - `24`: Add a new key with the given descripton.
-    ///        app_cfg (in): 1 byte: the security level +
-    ///                      1 byte: the key ID mode +
-    ///                      9 bytes: the key ID (might not use all bytes) +
-    ///                      16 bytes: the key.
-    /// - `25`: Remove the key at an index.
-    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
-        match command_num {
-            0 => ReturnCode::SUCCESS,
-            1 => {
-                if self.mac.is_on() {
+            48 => {
+                self.do_with_app(appid, |app| {
+                    let fits = app.app_write
+                        .as_ref()
+                        .map_or(false, |payload| arg1 <= payload.len());
+                    if !app.persistent_buffers || !fits {
+                        return ReturnCode::EINVAL;
+                    }
+                    app.tx_len = Some(arg1);
+                    ReturnCode::SUCCESS
+                })
+            }
+            49 => {
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: (self.mac.get_max_retries() as usize) + 1 }
+            }
+            50 => {
+                self.mac.set_pan_coordinator(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
+            51 => {
+                self.rx_gap.set(arg1 as u32);
+                ReturnCode::SUCCESS
+            }
+            52 => {
+                self.do_with_cfg_mut(appid, LIMITS_LEN, |cfg| {
+                    let limits = [1 + TX_QUEUE_LEN,
+                                  self.mac.get_receive_buffer_count(),
+                                  MAX_NEIGHBORS,
+                                  MAX_KEYS,
+                                  MAX_NOISE_WINDOW,
+                                  MAX_HEADER_IES,
+                                  MAX_PAYLOAD_IES,
+                                  MAX_PAYLOAD_PATTERN,
+                                  MAX_AGGREGATE_LEN,
+                                  radio::MAX_BUF_SIZE,
+                                  DUTY_CYCLE_BUCKETS];
+                    for (byte, limit) in cfg.iter_mut().zip(limits.iter()) {
+                        *byte = *limit as u8;
+                    }
+                    ReturnCode::SUCCESS
+                })
+            }
+            53 => {
+                self.count_only.set(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
+            54 => {
+                let value = match arg1 {
+                    0 => self.counted_frames.get() as usize,
+                    1 => self.counted_octets.get() as usize,
+                    2 => {
+                        self.counted_sources
+                            .get()
+                            .iter()
+                            .filter(|source| source.is_some())
+                            .count()
+                    }
+                    _ => return ReturnCode::EINVAL,
+                };
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: value + 1 }
+            }
+            55 => {
+                self.reset_frame_counters();
+                ReturnCode::SUCCESS
+            }
+            56 => {
+                match OversizedPolicy::from_usize(arg1) {
+                    Some(policy) => {
+                        self.do_with_app(appid, |app| {
+                            app.oversized = policy;
+                            ReturnCode::SUCCESS
+                        })
+                    }
+                    None => ReturnCode::EINVAL,
+                }
+            }
+            57 => {
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: self.oversized_frames.get() as usize + 1 }
+            }
+            58 => {
+                let fallback = self.fallback_app.get();
+                if arg1 == 0 {
+                    if fallback == Some(appid) {
+                        self.fallback_app.set(None);
+                    }
                     ReturnCode::SUCCESS
+                } else if fallback.map_or(false, |fallback| fallback != appid) {
+                    ReturnCode::EBUSY
                 } else {
-                    ReturnCode::EOFF
+                    self.fallback_app.set(Some(appid));
+                    ReturnCode::SUCCESS
                 }
             }
-            2 => {
-                self.mac.set_address(arg1 as u16);
+            59 => {
+                self.mac.set_underrun_retry(arg1 != 0);
                 ReturnCode::SUCCESS
             }
-            3 => {
-                self.do_with_cfg(appid, 8, |cfg| {
-                    let mut addr_long = [0u8; 8];
-                    addr_long.copy_from_slice(cfg);
-                    self.mac.set_address_long(addr_long);
+            60 => {
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: self.mac.get_underrun_count() as usize + 1 }
+            }
+            61 => {
+                if arg1 > u8::max_value() as usize {
+                    return ReturnCode::EINVAL;
+                }
+                self.do_with_app(appid, |app| {
+                    app.priority = arg1 as u8;
                     ReturnCode::SUCCESS
                 })
             }
-            4 => {
-                self.mac.set_pan(arg1 as u16);
+            62 => {
+                self.mac.get_last_cca().map_or(ReturnCode::FAIL, |clear| {
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: (clear as usize) + 1 }
+                })
+            }
+            63 => {
+                self.mac.get_last_cca().map_or(ReturnCode::FAIL, |_| {
+                    let elapsed = self.alarm.now().wrapping_sub(self.cca_at.get());
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: Self::tics_to_ms(elapsed) as usize + 1 }
+                })
+            }
+            64 => {
+                let mode = match arg1 {
+                    0 => radio::SensitivityMode::HighSensitivity,
+                    1 => radio::SensitivityMode::HighDynamicRange,
+                    2 => radio::SensitivityMode::ManualGain,
+                    _ => return ReturnCode::EINVAL,
+                };
+                self.mac.set_sensitivity_mode(mode)
+            }
+            65 => {
+                let result = self.do_with_app(appid, |app| {
+                    app.app_read.as_mut().map_or(ReturnCode::EINVAL, |rbuf| {
+                        let rbuf = rbuf.as_mut();
+                        if rbuf.len() < 2 * RSSI_BINS {
+                            return ReturnCode::ESIZE;
+                        }
+                        for (i, count) in self.rssi_histogram.get().iter().enumerate() {
+                            rbuf[2 * i] = *count as u8;
+                            rbuf[2 * i + 1] = (*count >> 8) as u8;
+                        }
+                        ReturnCode::SUCCESS
+                    })
+                });
+                if result == ReturnCode::SUCCESS && arg1 != 0 {
+                    self.rssi_histogram.set([0; RSSI_BINS]);
+                }
+                result
+            }
+            66 => {
+                self.mac.set_listen_only(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
+            67 => {
+                let driver_features = [self.mac.is_listen_only(),
+                                       self.mac.is_pan_coordinator(),
+                                       self.mac.is_software_crc(),
+                                       self.count_only.get(),
+                                       self.duty_cycle.get() != 0,
+                                       self.noise_interval.get() != 0,
+                                       self.rx_gap.get() != 0,
+                                       self.num_keys.get() != 0,
+                                       self.fallback_app.get().is_some(),
+                                       self.mac.is_promiscuous(),
+                                       self.suppress_duplicates.get(),
+                                       self.src_addr_long.get(),
+                                       self.energy_budget.get().is_some(),
+                                       self.mac.is_auto_ack(),
+                                       self.tracing.get(),
+                                       !self.mac.is_rx_on_when_idle()];
+                let mut features = 0;
+                for (bit, enabled) in driver_features.iter().enumerate() {
+                    if *enabled {
+                        features |= 1 << bit;
+                    }
+                }
+                self.do_with_app(appid, |app| {
+                    let app_features = [app.aggregate,
+                                        app.persistent_buffers,
+                                        app.rx_length_prefix,
+                                        app.check_dst,
+                                        app.src_filter.is_some(),
+                                        app.pattern.is_some(),
+                                        app.seq_range.is_some(),
+                                        app.rx_crc_errors,
+                                        app.rx_paused,
+                                        app.rx_hold,
+                                        app.tx_offset != 0,
+                                        app.rx_interval != 0];
+                    for (bit, enabled) in app_features.iter().enumerate() {
+                        if *enabled {
+                            features |= 1 << (16 + bit);
+                        }
+                    }
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: features + 1 }
+                })
+            }
+            68 => {
+                self.do_with_app(appid, |app| {
+                    app.seq_range = Some((arg1 as u8, (arg1 >> 8) as u8));
+                    ReturnCode::SUCCESS
+                })
+            }
+            69 => {
+                self.do_with_app(appid, |app| {
+                    app.seq_range = None;
+                    ReturnCode::SUCCESS
+                })
+            }
+            70 => {
+                if arg1 > MAX_PAYLOAD_LEN {
+                    return ReturnCode::EINVAL;
+                }
+                self.marginal_payload_len.set(arg1);
+                ReturnCode::SUCCESS
+            }
+            71 => {
+                self.marginal_rssi.set(arg1 as i8);
+                ReturnCode::SUCCESS
+            }
+            72 => {
+                if arg1 == 0 {
+                    return ReturnCode::EINVAL;
+                }
+                self.marginal_failures.set(arg1 as u32);
+                ReturnCode::SUCCESS
+            }
+            73 => {
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: self.payload_limit() + 1 }
+            }
+            74 => {
+                if arg1 > u8::max_value() as usize {
+                    return ReturnCode::EINVAL;
+                }
+                self.mac.get_crc_error_count(arg1 as u8).map_or(ReturnCode::EINVAL, |count| {
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: count as usize + 1 }
+                })
+            }
+            75 => {
+                self.mac.reset_crc_error_counts();
+                ReturnCode::SUCCESS
+            }
+            76 => {
+                self.do_with_app(appid, |app| {
+                    app.rx_quality.map_or(ReturnCode::FAIL, |(rssi, lqi)| {
+                        let quality = (rssi as u8 as usize) | ((lqi as usize) << 8);
+                        // Guarantee that the value is positive by adding 1
+                        ReturnCode::SuccessWithValue { value: quality + 1 }
+                    })
+                })
+            }
+            77 => {
+                if self.current_app.get().is_some() {
+                    ReturnCode::EBUSY
+                } else {
+                    self.mac.stop()
+                }
+            }
+            78 => self.mac.start(),
+            80 => {
+                if arg1 > 255 {
+                    return ReturnCode::EINVAL;
+                }
+                self.mac.set_tx_retries(arg1 as u8);
+                ReturnCode::SUCCESS
+            }
+            81 => {
+                if self.current_app.get().is_some() || self.cca_app.get().is_some() ||
+                   self.scan_app.get().is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let result = self.mac.read_rssi();
+                if result == ReturnCode::SUCCESS {
+                    self.cca_app.set(Some(appid));
+                }
+                result
+            }
+            82 => {
+                self.cca_threshold.set(arg1 as i8);
                 ReturnCode::SUCCESS
             }
-            5 => self.mac.set_channel(arg1 as u8),
-            6 => {
-                // Userspace casts the i8 to a u8 before casting to u32, so this works.
-                self.mac.set_tx_power(arg1 as i8);
+            83 => {
+                let result = self.do_with_app(appid, |app| {
+                    app.rx_crc_errors = arg1 != 0;
+                    ReturnCode::SUCCESS
+                });
+                self.update_deliver_crc_errors();
+                result
+            }
+            84 => {
+                self.mac.set_promiscuous(arg1 != 0);
                 ReturnCode::SUCCESS
             }
-            7 => {
-                self.mac.config_commit();
+            85 => {
+                self.mac.set_data_sequence_increment(arg1 != 0);
                 ReturnCode::SUCCESS
             }
-            8 => {
-                // Guarantee that address is positive by adding 1
-                let addr = self.mac.get_address();
-                ReturnCode::SuccessWithValue { value: (addr as usize) + 1 }
+            86 => {
+                self.mac.get_last_tx_sequence().map_or(ReturnCode::FAIL, |seq| {
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: (seq as usize) + 1 }
+                })
             }
-            9 => {
-                self.do_with_cfg_mut(appid, 8, |cfg| {
-                    cfg.copy_from_slice(&self.mac.get_address_long());
+            87 => self.start_loopback(appid),
+            88 => {
+                self.do_with_app(appid, |app| {
+                    app.rx_paused = arg1 == 0;
                     ReturnCode::SUCCESS
                 })
             }
-            10 => {
-                // Guarantee that the PAN is positive by adding 1
-                let pan = self.mac.get_pan();
-                ReturnCode::SuccessWithValue { value: (pan as usize) + 1 }
+            89 => {
+                if self.mac.is_listen_only() {
+                    return ReturnCode::EUNINSTALLED;
+                }
+                self.do_with_app(appid, |app| {
+                    if app.pending_tx.is_some() &&
+                       (app.aggregate || app.tx_queue_len == TX_QUEUE_LEN) {
+                        return ReturnCode::EBUSY;
+                    }
+                    if !self.tx_permitted(Self::estimate_airtime(0)) {
+                        return ReturnCode::ERESERVE;
+                    }
+                    let empty = QueuedTx {
+                        tx: (MacAddress::Short(arg1 as u16), None),
+                        payload_len: 0,
+                        no_ack: false,
+                    };
+                    if app.pending_tx.is_some() {
+                        return match self.push_tx(appid, app, empty) {
+                            Some(_) => ReturnCode::SUCCESS,
+                            None => ReturnCode::ENOMEM,
+                        };
+                    }
+                    // Sent like a transmission whose payload is empty
+                    app.pending_tx = Some(empty.tx);
+                    app.pending_empty = true;
+
+                    self.do_next_tx_sync(appid)
+                })
             }
-            11 => {
-                // Guarantee that the channel is positive by adding 1
-                let channel = self.mac.get_channel();
-                ReturnCode::SuccessWithValue { value: (channel as usize) + 1 }
+            90 => {
+                let capabilities = self.mac.get_capabilities() as usize;
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: capabilities + 1 }
             }
-            12 => {
-                // Cast the power to unsigned, then ensure it is positive
-                let power = self.mac.get_tx_power() as u8;
-                ReturnCode::SuccessWithValue { value: (power as usize) + 1 }
+            91 => {
+                let enabled = arg1 != 0;
+                if !enabled {
+                    self.seen_frames.set([None; MAX_SEEN_FRAMES]);
+                    self.seen_next.set(0);
+                }
+                self.suppress_duplicates.set(enabled);
+                ReturnCode::SUCCESS
             }
-            13 => {
-                // Guarantee that it is positive by adding 1
-                ReturnCode::SuccessWithValue { value: MAX_NEIGHBORS + 1 }
+            92 => {
+                if self.current_app.get().is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                self.src_addr_long.set(arg1 != 0);
+                ReturnCode::SUCCESS
             }
-            14 => {
-                // Guarantee that it is positive by adding 1
-                ReturnCode::SuccessWithValue { value: self.num_neighbors.get() + 1 }
+            93 => {
+                let crc_errors = (11..27).fold(0u32, |sum, channel| {
+                    sum.wrapping_add(self.mac.get_crc_error_count(channel).unwrap_or(0))
+                });
+                let stats = [self.stats.tx_frames.get(),
+                             self.stats.tx_success.get(),
+                             self.stats.tx_failures.get(),
+                             self.stats.rx_frames.get(),
+                             crc_errors,
+                             self.stats.rx_no_buffer.get()];
+                self.do_with_cfg_mut(appid, STATS_LEN, |cfg| {
+                    for (bytes, count) in cfg.chunks_mut(4).zip(stats.iter()) {
+                        for (i, byte) in bytes.iter_mut().enumerate() {
+                            *byte = (count >> (8 * i)) as u8;
+                        }
+                    }
+                    ReturnCode::SUCCESS
+                })
             }
-            15 => {
-                self.get_neighbor(arg1)
-                    .map_or(ReturnCode::EINVAL, |neighbor| {
-                        ReturnCode::SuccessWithValue { value: (neighbor.short_addr as usize) + 1 }
-                    })
+            94 => {
+                if self.current_app.get().is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                self.mac.set_address(arg1 as u16);
+                self.mac.set_pan((arg1 >> 16) as u16);
+                self.config_changed.set(self.config_changed.get() | CONFIG_SHORT_ADDRESS |
+                                        CONFIG_PAN);
+                self.config_commit();
+                ReturnCode::SUCCESS
             }
-            16 => {
-                self.do_with_cfg_mut(appid, 8, |cfg| {
-                    self.get_neighbor(arg1)
-                        .map_or(ReturnCode::EINVAL, |neighbor| {
-                            cfg.copy_from_slice(&neighbor.long_addr);
-                            ReturnCode::SUCCESS
-                        })
+            95 => {
+                let eui64 = match self.mac.get_eui64() {
+                    Some(eui64) => eui64,
+                    None => return ReturnCode::ENOSUPPORT,
+                };
+                self.do_with_app(appid, |app| {
+                    app.app_cfg.as_mut().map_or(ReturnCode::EINVAL, |cfg| {
+                        if cfg.len() < eui64.len() {
+                            return ReturnCode::ESIZE;
+                        }
+                        cfg.as_mut()[..eui64.len()].copy_from_slice(&eui64);
+                        ReturnCode::SUCCESS
+                    })
                 })
             }
-            17 => {
-                self.do_with_cfg(appid, 8, |cfg| {
-                    let mut new_neighbor: DeviceDescriptor = Default::default();
-                    new_neighbor.short_addr = arg1 as u16;
-                    new_neighbor.long_addr.copy_from_slice(cfg);
-                    self.add_neighbor(new_neighbor)
-                        .map_or(ReturnCode::EINVAL,
-                                |index| ReturnCode::SuccessWithValue { value: index + 1 })
+            96 => {
+                if self.mac.is_listen_only() {
+                    return ReturnCode::EUNINSTALLED;
+                }
+                if self.radio_in_use() {
+                    return ReturnCode::EBUSY;
+                }
+                self.do_with_app(appid, |app| {
+                    if app.pending_tx.is_some() {
+                        return ReturnCode::EBUSY;
+                    }
+                    let result = app.with_tx_payload(|raw| {
+                        if !self.tx_permitted(Self::estimate_airtime(raw.len())) {
+                            return ReturnCode::ERESERVE;
+                        }
+                        self.kernel_tx.take().map_or(ReturnCode::ENOMEM, |kbuf| {
+                            let frame = match self.mac.prepare_raw_frame(kbuf, raw) {
+                                Ok(frame) => frame,
+                                Err(kbuf) => {
+                                    self.kernel_tx.replace(kbuf);
+                                    return ReturnCode::EINVAL;
+                                }
+                            };
+                            let (result, mbuf) = self.mac.transmit(frame);
+                            if let Some(buf) = mbuf {
+                                self.kernel_tx.replace(buf);
+                            }
+                            result
+                        })
+                    });
+                    let result = result.unwrap_or(ReturnCode::EINVAL);
+                    if result == ReturnCode::SUCCESS {
+                        RadioStats::increment(&self.stats.tx_frames);
+                        self.current_app.set(Some(appid));
+                        self.last_tx_idx.set(appid.idx());
+                        self.tx_started.set(self.alarm.now());
+                        self.tx_no_ack.set(false);
+                        self.last_tx_error.set(None);
+                        self.trace(TraceEvent::TxStarted, appid.idx() as u8, 0);
+                    } else {
+                        RadioStats::increment(&self.stats.tx_failures);
+                    }
+                    result
                 })
             }
-            18 => self.remove_neighbor(arg1),
-            19 => {
-                // Guarantee that it is positive by adding 1
-                ReturnCode::SuccessWithValue { value: MAX_KEYS + 1 }
+            97 => {
+                if arg1 > 0xffffff {
+                    return ReturnCode::EINVAL;
+                }
+                self.mac.set_csma_params(arg1 as u8, (arg1 >> 8) as u8, (arg1 >> 16) as u8)
             }
-            20 => {
-                // Guarantee that it is positive by adding 1
-                ReturnCode::SuccessWithValue { value: self.num_keys.get() + 1 }
+            98 => {
+                let in_flight = self.current_app.get() == Some(appid);
+                let aborted = in_flight && self.abort_in_flight();
+                let result = self.do_with_app(appid, |app| {
+                    if !self.cancel_tx(app) && !aborted {
+                        return if in_flight {
+                            ReturnCode::EBUSY
+                        } else {
+                            ReturnCode::EALREADY
+                        };
+                    }
+                    if !in_flight || aborted {
+                        app.tx_callback
+                            .take()
+                            .map(|mut cb| cb.schedule(ReturnCode::ECANCEL.into(), 0, 0));
+                    }
+                    ReturnCode::SUCCESS
+                });
+                // The radio is free for the other apps again
+                if aborted {
+                    self.do_next_tx_async();
+                }
+                result
             }
-            21 => {
-                self.get_key(arg1)
-                    .map_or(ReturnCode::EINVAL,
-                            |key| ReturnCode::SuccessWithValue { value: (key.level as usize) + 1 })
+            99 => {
+                let budget = if arg1 == 0 { None } else { Some(arg1 as u32) };
+                self.energy_budget.set(budget);
+                ReturnCode::SUCCESS
             }
-            22 => {
-                self.do_with_cfg_mut(appid, 10, |cfg| {
-                    self.get_key(arg1)
-                        .and_then(|key| encode_key_id(&key.key_id, cfg).done())
-                        .map_or(ReturnCode::EINVAL, |_| ReturnCode::SUCCESS)
+            100 => {
+                self.energy_budget.get().map_or(ReturnCode::FAIL, |left| {
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: left as usize + 1 }
                 })
             }
-            23 => {
-                self.do_with_cfg_mut(appid, 16, |cfg| {
-                    self.get_key(arg1)
-                        .map_or(ReturnCode::EINVAL, |key| {
-                            cfg.copy_from_slice(&key.key);
-                            ReturnCode::SUCCESS
-                        })
+            101 => {
+                self.do_with_app(appid, |app| {
+                    app.rx_hold = arg1 != 0;
+                    if !app.rx_hold {
+                        self.discard_held_frames(app);
+                    }
+                    ReturnCode::SUCCESS
                 })
             }
-            24 => {
-                self.do_with_cfg(appid, 27, |cfg| {
-                    KeyDescriptor::decode(cfg)
-                        .done()
-                        .and_then(|(_, new_key)| self.add_key(new_key))
-                        .map(|index| ReturnCode::SuccessWithValue { value: index + 1 })
-                        .unwrap_or(ReturnCode::EINVAL)
-                })
+            102 => self.do_with_app(appid, |app| self.fetch_held_frame(app)),
+            103 => {
+                let reason = self.last_tx_error.get().map_or(0, |err| err as usize);
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: reason + 1 }
             }
-            25 => self.remove_key(arg1),
-            26 => {
+            104 => {
+                self.mac.set_auto_ack(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
+            105 => {
                 self.do_with_app(appid, |app| {
-                    if app.pending_tx.is_some() {
-                        // Cannot support more than one pending tx per process.
+                    if app.pending_tx.is_some() || app.tx_queue_len > 0 {
                         return ReturnCode::EBUSY;
                     }
-                    let next_tx = app.app_cfg.as_ref().and_then(|cfg| {
-                        if cfg.len() != 11 {
-                            return None;
+                    if app.app_write.as_ref().map_or(false, |payload| arg1 > payload.len()) {
+                        return ReturnCode::ESIZE;
+                    }
+                    app.tx_offset = arg1;
+                    ReturnCode::SUCCESS
+                })
+            }
+            106 => {
+                if arg1 != 0 && !self.tracing.get() {
+                    self.trace.set([None; TRACE_LEN]);
+                    self.trace_next.set(0);
+                }
+                self.tracing.set(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
+            107 => {
+                self.do_with_cfg_mut(appid, TRACE_LEN * TRACE_ENTRY_LEN, |cfg| {
+                    let trace = self.trace.get();
+                    let next = self.trace_next.get();
+                    let events = (0..TRACE_LEN).filter_map(|i| trace[(next + i) % TRACE_LEN]);
+                    for byte in cfg.iter_mut() {
+                        *byte = 0;
+                    }
+                    let mut count = 0;
+                    for (bytes, (time, event, detail, arg)) in
+                        cfg.chunks_mut(TRACE_ENTRY_LEN).zip(events) {
+                        for i in 0..4 {
+                            bytes[i] = (time >> (8 * i)) as u8;
                         }
-                        let dst_addr = arg1 as u16;
-                        let level = match SecurityLevel::from_scf(cfg.as_ref()[0]) {
-                            Some(level) => level,
-                            None => {
-                                return None;
-                            }
-                        };
-                        if level == SecurityLevel::None {
-                            Some((dst_addr, None))
-                        } else {
-                            let key_id = match decode_key_id(&cfg.as_ref()[1..]).done() {
-                                Some((_, key_id)) => key_id,
-                                None => {
-                                    return None;
-                                }
-                            };
-                            Some((dst_addr, Some((level, key_id))))
+                        bytes[4] = event as u8;
+                        bytes[5] = detail;
+                        bytes[6] = arg as u8;
+                        bytes[7] = (arg >> 8) as u8;
+                        count += 1;
+                    }
+                    // Guarantee that the value is positive by adding 1
+                    ReturnCode::SuccessWithValue { value: count + 1 }
+                })
+            }
+            108 => {
+                let address = self.mac.get_address();
+                let pan = self.mac.get_pan();
+                let mut config = [0u8; CONFIG_LEN];
+                config[0] = self.mac.get_channel();
+                config[1] = self.mac.get_tx_power() as u8;
+                config[2] = address as u8;
+                config[3] = (address >> 8) as u8;
+                config[4] = pan as u8;
+                config[5] = (pan >> 8) as u8;
+                config[6] = self.src_addr_long.get() as u8;
+                config[7] = self.mac.is_on() as u8;
+                config[8..].copy_from_slice(&self.mac.get_address_long());
+                self.do_with_app(appid, |app| {
+                    app.app_cfg.as_mut().map_or(ReturnCode::EINVAL, |cfg| {
+                        if cfg.len() < CONFIG_LEN {
+                            return ReturnCode::ESIZE;
                         }
-                    });
-                    if next_tx.is_none() {
-                        return ReturnCode::EINVAL;
+                        cfg.as_mut()[..CONFIG_LEN].copy_from_slice(&config);
+                        ReturnCode::SUCCESS
+                    })
+                })
+            }
+            109 => self.start_scan(appid),
+            110 => {
+                let energies = self.scan_results.get();
+                self.do_with_cfg_mut(appid, mac::NUM_CHANNELS, |cfg| {
+                    for (byte, energy) in cfg.iter_mut().zip(energies.iter()) {
+                        *byte = *energy as u8;
                     }
-                    app.pending_tx = next_tx;
-
-                    self.do_next_tx_sync(appid)
+                    ReturnCode::SUCCESS
+                })
+            }
+            111 => {
+                if self.fallback_app.get() == Some(appid) {
+                    self.fallback_app.set(None);
+                }
+                let aborted = self.current_app.get() == Some(appid) && self.abort_in_flight();
+                self.release_buffers(appid);
+                let result = self.do_with_app(appid, |app| {
+                    *app = App::default();
+                    ReturnCode::SUCCESS
+                });
+                self.update_deliver_crc_errors();
+                if aborted {
+                    self.do_next_tx_async();
+                }
+                result
+            }
+            112 => {
+                self.do_with_app(appid, |app| {
+                    app.rx_interval = (arg1 & RX_INTERVAL_MASK) as u32;
+                    app.rx_interval_hold = arg1 & RX_INTERVAL_HOLD != 0;
+                    app.rx_last_callback = None;
+                    app.rx_suppressed = 0;
+                    ReturnCode::SUCCESS
                 })
             }
+            113 => {
+                self.mac.set_rx_on_when_idle(arg1 == 0);
+                ReturnCode::SUCCESS
+            }
+            114 => {
+                let state = self.mac.get_power_state() as usize;
+                // Guarantee that the value is positive by adding 1
+                ReturnCode::SuccessWithValue { value: state + 1 }
+            }
+            115 => {
+                self.mac.set_raw_fcs(arg1 != 0);
+                ReturnCode::SUCCESS
+            }
             _ => ReturnCode::ENOSUPPORT,
         }
     }
 }
 
-impl<'a> mac::TxClient for RadioDriver<'a> {
+impl<'a, A: time::Alarm + 'a> mac::TxClient for RadioDriver<'a, A> {
     fn send_done(&self, spi_buf: &'static mut [u8], acked: bool, result: ReturnCode) {
         self.kernel_tx.replace(spi_buf);
+        if self.loopback_tx.get() {
+            // The self-test frame is not an app transmission
+            self.loopback_tx.set(false);
+            if result == ReturnCode::SUCCESS {
+                self.start_timer(Timer::Loopback, LOOPBACK_TIMEOUT_MS);
+            } else {
+                self.finish_loopback(result);
+            }
+            return;
+        }
+        if self.current_app.get().is_none() {
+            // This frame was aborted, and nobody is waiting for it
+            return;
+        }
+        self.trace(TraceEvent::TxDone, isize::from(result) as u8, acked as u16);
+        let now = self.alarm.now();
+        self.tx_done_at.set(now);
+        self.cca_at.set(now);
+        self.last_tx_error.set(TxError::from_result(result,
+                                                    acked,
+                                                    self.tx_no_ack.get(),
+                                                    self.mac.get_last_cca()));
+        if result == ReturnCode::SUCCESS && (acked || self.tx_no_ack.get()) {
+            RadioStats::increment(&self.stats.tx_success);
+        } else {
+            RadioStats::increment(&self.stats.tx_failures);
+        }
+        if self.tx_no_ack.get() {
+            // Frames nobody acknowledges say nothing about the link quality
+        } else if result == ReturnCode::SUCCESS && acked {
+            self.link_failures.set(0);
+        } else {
+            self.link_failures.set(self.link_failures.get().saturating_add(1));
+        }
+        let airtime = Self::tics_to_us(now.wrapping_sub(self.tx_started.get()));
+        self.record_airtime(airtime);
+        self.charge_tx_energy(airtime);
         self.current_app.get().map(|appid| {
             let _ = self.apps.enter(appid, |app, _| {
+                let retries = self.mac.get_tx_retries_used() as usize;
+                let pending = acked && self.mac.get_ack_frame_pending();
+                let ack_flags = (acked as usize) | ((pending as usize) << 1);
+                let error = self.last_tx_error.get();
+                app.app_tx_status.as_mut().map(|status| {
+                    encode_tx_status(result, ack_flags, retries, error, now, status.as_mut())
+                });
                 app.tx_callback
                     .take()
-                    .map(|mut cb| cb.schedule(result.into(), acked as usize, 0));
+                    .map(|mut cb| cb.schedule(result.into(), ack_flags, retries));
             });
         });
         self.current_app.set(None);
@@ -792,12 +4431,103 @@ impl<'a> mac::TxClient for RadioDriver<'a> {
     }
 }
 
+impl<'a, A: time::Alarm + 'a> mac::MeasurementClient for RadioDriver<'a, A> {
+    fn rssi_done(&self, rssi: i8, result: ReturnCode) {
+        if self.scan_app.get().is_some() {
+            // Scan measurements are on other channels than the current one
+            self.scan_rssi_done(rssi, result);
+            return;
+        }
+        if result == ReturnCode::SUCCESS {
+            self.add_noise_sample(rssi);
+        }
+        self.cca_app.take().map(|appid| {
+            let clear = rssi < self.cca_threshold.get();
+            let _ = self.apps.enter(appid, |app, _| {
+                app.cca_callback
+                    .take()
+                    .map(|mut cb| cb.schedule(result.into(), clear as usize, rssi as u8 as usize));
+            });
+        });
+    }
+}
+
+impl<'a, A: time::Alarm + 'a> mac::ConfigClient for RadioDriver<'a, A> {
+    fn config_done(&self, result: ReturnCode) {
+        // Commits the driver makes on its own, such as while scanning, are
+        // not reported
+        if !self.config_requested.get() {
+            return;
+        }
+        self.config_requested.set(false);
+        let changed = self.config_changed.get();
+        self.config_changed.set(0);
+        self.apps.each(|app| {
+            app.config_callback
+                .as_mut()
+                .map(|cb| cb.schedule(result.into(), changed, 0));
+        });
+    }
+}
+
+impl<'a, A: time::Alarm + 'a> mac::PowerClient for RadioDriver<'a, A> {
+    fn changed(&self, on: bool) {
+        self.apps.each(|app| {
+            app.ready_callback
+                .as_mut()
+                .map(|cb| cb.schedule(on as usize, 0, 0));
+        });
+    }
+}
+
+impl<'a, A: time::Alarm + 'a> time::Client for RadioDriver<'a, A> {
+    fn fired(&self) {
+        let now = self.alarm.now();
+        let mut timers = self.timers.get();
+        let mut expired = [false; NUM_TIMERS];
+        for (i, deadline) in timers.iter_mut().enumerate() {
+            let passed = deadline.map_or(false, |d| now.wrapping_sub(d) as i32 >= 0);
+            if passed {
+                *deadline = None;
+                expired[i] = true;
+            }
+        }
+        self.timers.set(timers);
+
+        for timer in TIMERS.iter() {
+            if expired[*timer as usize] {
+                self.timer_fired(*timer);
+            }
+        }
+        self.arm_alarm();
+    }
+}
+
 /// Encode two PAN IDs into a single usize.
 #[inline]
 fn encode_pans(dst_pan: &Option<PanID>, src_pan: &Option<PanID>) -> usize {
     ((dst_pan.unwrap_or(0) as usize) << 16) | (src_pan.unwrap_or(0) as usize)
 }
 
+/// Bit set in the encoded destination address of a received frame that was
+/// addressed to this node, when requested by the app.
+const ADDRESSED_TO_US: usize = 1 << 24;
+/// Bit set in the encoded destination address of a received frame that was
+/// truncated to fit into the read buffer.
+const TRUNCATED: usize = 1 << 25;
+/// Bit set in the encoded destination address of a received frame whose FCS
+/// was invalid, for apps that asked to receive such frames.
+const CRC_INVALID: usize = 1 << 26;
+/// Bit set in the encoded destination address of a received frame that could
+/// not be delivered because the app has no read buffer.
+const NO_BUFFER: usize = 1 << 27;
+/// Position in the encoded destination address of a received frame of the
+/// number of frames the rate limit suppressed since the previous rx callback.
+const SUPPRESSED_SHIFT: usize = 28;
+/// Largest suppressed frame count that can be reported; larger counts
+/// saturate.
+const MAX_SUPPRESSED: usize = 15;
+
 /// Encodes as much as possible about an address into a single usize.
 #[inline]
 fn encode_address(addr: &Option<MacAddress>) -> usize {
@@ -808,27 +4538,102 @@ fn encode_address(addr: &Option<MacAddress>) -> usize {
     ((AddressMode::from(addr) as usize) << 16) | short_addr_only
 }
 
-impl<'a> mac::RxClient for RadioDriver<'a> {
+impl<'a, A: time::Alarm + 'a> mac::RxClient for RadioDriver<'a, A> {
+    /// Received frames are copied into the read buffer of every app whose
+    /// filters accept them, and of the fallback app if there is one and no
+    /// other app accepted the frame. The entire frame is copied over,
+    /// preceded by two bytes: the data offset and the data length, so that
+    /// the payload is at `rbuf[data_offset..]`.
+    ///
+    /// If the app has enabled the length prefix, everything above is shifted
+    /// over by one byte, and `rbuf[0]` holds the number of bytes that follow
+    /// it for this frame. The data offset remains relative to `rbuf[1]`.
+    ///
+    /// Apps with aggregation enabled receive each payload of an aggregated
+    /// frame separately, one per rx callback, in the same format but without
+    /// the MAC header: the data offset is always 2. The payload of an
+    /// aggregated frame is the dispatch byte 0x3A followed by sub-frames, each
+    /// a length byte followed by that many payload bytes. Payloads that have
+    /// not been delivered when the next aggregated frame arrives are dropped.
+    ///
+    /// If an app that accepts a frame has no read buffer, its rx callback is
+    /// still invoked, with bit 27 of the encoded destination address set, so
+    /// that it learns that a frame was lost.
+    ///
+    /// If an app has set a minimum interval between rx callbacks with command
+    /// 112, bits 28 to 31 of the encoded destination address report how many
+    /// frames were dropped or held since its previous rx callback, up to 15.
     fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+        // Capture the timestamp first to keep its jitter low
+        self.rx_timestamp.set(self.alarm.now());
+        // The payload bounds are computed from the received PHR, so make sure
+        // they actually lie within the buffer before slicing it.
+        if data_offset > buf.len() || data_len > buf.len() - data_offset {
+            return;
+        }
+        self.trace(TraceEvent::Received,
+                   self.mac.get_rx_crc_valid() as u8,
+                   data_len as u16);
+        if self.loopback_app.get().is_some() &&
+           &buf[data_offset..data_offset + data_len] == &LOOPBACK_PAYLOAD[..] {
+            self.finish_loopback(ReturnCode::SUCCESS);
+            return;
+        }
+        let rx_gap = self.rx_gap.get();
+        if rx_gap > 0 &&
+           self.alarm.now().wrapping_sub(self.tx_done_at.get()) < Self::us_to_tics(rx_gap) {
+            self.trace(TraceEvent::Dropped, TRACE_DROP_RX_GAP, 0);
+            return;
+        }
+        let crc_valid = self.mac.get_rx_crc_valid();
+        if crc_valid && self.suppress_duplicates.get() && self.seen_recently(&header) {
+            self.trace(TraceEvent::Dropped, TRACE_DROP_DUPLICATE, 0);
+            return;
+        }
+        if crc_valid {
+            RadioStats::increment(&self.stats.rx_frames);
+            self.count_frame(&header, data_offset + data_len);
+        }
+        let rssi = self.mac.get_rx_rssi();
+        self.add_rssi_sample(rssi);
+        self.update_link_rssi(rssi);
+        if self.count_only.get() {
+            return;
+        }
+        let addressed_to_us = self.addressed_to_us(&header);
+        let payload = &buf[data_offset..data_offset + data_len];
+        let fallback = self.fallback_app.get();
+        let claimed = Cell::new(false);
         self.apps.each(|app| {
-            app.app_read
-                .take()
-                .as_mut()
-                .map(|rbuf| {
-                    let rbuf = rbuf.as_mut();
-                    let len = min(rbuf.len(), data_offset + data_len);
-                    // Copy the entire frame over to userland, preceded by two
-                    // bytes: the data offset and the data length.
-                    rbuf[..len].copy_from_slice(&buf[..len]);
-                    rbuf[0] = data_offset as u8;
-                    rbuf[1] = data_len as u8;
-
-                    // Encode useful parts of the header in 3 usizes
-                    let pans = encode_pans(&header.dst_pan, &header.src_pan);
-                    let dst_addr = encode_address(&header.dst_addr);
-                    let src_addr = encode_address(&header.src_addr);
-                    app.rx_callback.take().map(|mut cb| cb.schedule(pans, dst_addr, src_addr));
-                });
+            if fallback == Some(app.appid()) || app.rx_paused ||
+               !(crc_valid || app.rx_crc_errors) || !self.accepts(app, &header, payload) {
+                return;
+            }
+            claimed.set(true);
+            let appid = app.appid();
+            self.deliver_frame(appid,
+                               app,
+                               buf,
+                               &header,
+                               data_offset,
+                               data_len,
+                               addressed_to_us,
+                               crc_valid);
+        });
+        fallback.map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                if !app.rx_paused && (crc_valid || app.rx_crc_errors) &&
+                   (!claimed.get() || self.accepts(app, &header, payload)) {
+                    self.deliver_frame(appid,
+                                       app,
+                                       buf,
+                                       &header,
+                                       data_offset,
+                                       data_len,
+                                       addressed_to_us,
+                                       crc_valid);
+                }
+            });
         });
     }
 }
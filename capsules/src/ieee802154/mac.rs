@@ -64,8 +64,9 @@
 //! ```
 
 use core::cell::Cell;
+use core::cmp::min;
 use kernel::ReturnCode;
-use kernel::common::take_cell::MapCell;
+use kernel::common::take_cell::{MapCell, TakeCell};
 use kernel::hil::radio;
 use net::ieee802154::*;
 use net::stream::{encode_u8, encode_u32, encode_bytes};
@@ -98,6 +99,8 @@ struct FrameInfo {
     data_len: usize,
     // The length of the MIC
     mic_len: usize,
+    // Whether the buffer already holds the FCS after the frame
+    fcs_included: bool,
 
     // Security level, key, and nonce
     security_params: Option<(SecurityLevel, [u8; 16], [u8; 13])>,
@@ -125,6 +128,20 @@ impl Frame {
 
         ReturnCode::SUCCESS
     }
+
+    /// Sets whether the frame asks its receiver for an acknowledgement. Data
+    /// frames are prepared requesting one, unless they are broadcast.
+    pub fn set_ack_requested(&mut self, ack_requested: bool) {
+        let fcf = (self.buf[radio::PSDU_OFFSET] as u16) |
+                  ((self.buf[radio::PSDU_OFFSET + 1] as u16) << 8);
+        let fcf = if ack_requested {
+            fcf | frame_control::ACK_REQUESTED
+        } else {
+            fcf & !frame_control::ACK_REQUESTED
+        };
+        self.buf[radio::PSDU_OFFSET] = fcf as u8;
+        self.buf[radio::PSDU_OFFSET + 1] = (fcf >> 8) as u8;
+    }
 }
 
 impl FrameInfo {
@@ -202,6 +219,31 @@ fn get_ccm_nonce(device_addr: &[u8; 8], frame_counter: u32, level: SecurityLevel
 /// - pads the m data to 16-byte blocks
 pub const CRYPT_BUF_SIZE: usize = radio::MAX_MTU + 3 * 16;
 
+/// Number of times a frame is retransmitted after a frame buffer underrun,
+/// when retransmission is enabled.
+const MAX_UNDERRUN_RETRIES: u8 = 3;
+
+/// The 2.4 GHz O-QPSK channels are numbered 11 to 26.
+pub const MIN_CHANNEL: u8 = 11;
+pub const NUM_CHANNELS: usize = 16;
+
+/// Whether the header of the frame in a transmit buffer has the ACK request
+/// bit set.
+fn requests_ack(buf: &[u8]) -> bool {
+    Header::decode(&buf[radio::PSDU_OFFSET..], false)
+        .done()
+        .map_or(false, |(_, (header, _))| header.ack_requested)
+}
+
+/// Maps a channel to its index in per-channel arrays.
+fn channel_index(channel: u8) -> Option<usize> {
+    if channel >= MIN_CHANNEL && ((channel - MIN_CHANNEL) as usize) < NUM_CHANNELS {
+        Some((channel - MIN_CHANNEL) as usize)
+    } else {
+        None
+    }
+}
+
 /// The contract satisfied by an implementation of an IEEE 802.15.4 MAC device.
 /// Any IEEE 802.15.4 MAC device should expose the following high-level
 /// functionality:
@@ -219,17 +261,44 @@ pub trait Mac<'a> {
     fn set_transmit_client(&self, client: &'a TxClient);
     /// Sets the receive client of this MAC device
     fn set_receive_client(&self, client: &'a RxClient);
+    /// Sets the measurement client of this MAC device
+    fn set_measurement_client(&self, client: &'a MeasurementClient);
+    /// Sets the power client of this MAC device
+    fn set_power_client(&self, client: &'a PowerClient);
+    /// Sets the configuration client of this MAC device
+    fn set_config_client(&self, client: &'a ConfigClient);
 
     /// The short 16-bit address of the MAC device
     fn get_address(&self) -> u16;
     /// The long 64-bit address (EUI-64) of the MAC device
     fn get_address_long(&self) -> [u8; 8];
+    /// The factory-programmed EUI-64 of the radio, if it has one
+    fn get_eui64(&self) -> Option<[u8; 8]>;
+    /// An identifier of the radio chip, if it reports one
+    fn get_chip_id(&self) -> Option<u32>;
     /// The 16-bit PAN ID of the MAC device
     fn get_pan(&self) -> u16;
     /// The 802.15.4 channel ID of the MAC device
     fn get_channel(&self) -> u8;
     /// The transmission power of the MAC device, in dBm
     fn get_tx_power(&self) -> i8;
+    /// The symbol rate of the radio's PHY mode, in symbols per second
+    fn get_symbol_rate(&self) -> u32;
+    /// The number of times an unacknowledged unicast frame is retransmitted
+    /// in total, by the radio and by `set_tx_retries`, saturating at 255
+    fn get_max_retries(&self) -> u8;
+    /// The features the radio supports, as a bitmask of `radio::CAP_*` bits
+    fn get_capabilities(&self) -> u32;
+    /// Whether the channel was clear at the radio's most recent clear channel
+    /// assessment, if one has been performed
+    fn get_last_cca(&self) -> Option<bool>;
+    /// Whether the acknowledgement of the most recent transmission had its
+    /// frame pending bit set
+    fn get_ack_frame_pending(&self) -> bool;
+    /// What the radio is currently doing
+    fn get_power_state(&self) -> radio::PowerState;
+    /// The frame version emitted in the headers of prepared frames
+    fn get_frame_version(&self) -> FrameVersion;
 
     /// Set the short 16-bit address of the MAC device
     fn set_address(&self, addr: u16);
@@ -243,6 +312,82 @@ pub trait Mac<'a> {
     /// Set the transmission power of the MAC device, in dBm. `power` should
     /// satisfy `-17 <= power <= 4`, otherwise EINVAL will be returned
     fn set_tx_power(&self, power: i8) -> ReturnCode;
+    /// Set the data sequence number (DSN) stamped on the next transmitted
+    /// frame. Subsequent frames increment from this value.
+    fn set_data_sequence(&self, seq: u8);
+    /// Set the frame version emitted in the headers of prepared frames.
+    /// Received frames with a newer frame version than this are dropped.
+    fn set_frame_version(&self, version: FrameVersion);
+    /// Compute the FCS of transmitted frames and check the FCS of received
+    /// frames in software instead of relying on the radio. Takes effect for
+    /// the radio after the next `config_commit`.
+    fn set_software_crc(&self, enabled: bool);
+    /// Whether the FCS is computed and checked in software
+    fn is_software_crc(&self) -> bool;
+    /// Expect raw frames passed to `prepare_raw_frame` to end in their own
+    /// FCS, which is transmitted as it is. The radio then no longer appends
+    /// the FCS, so it is computed in software for all other frames. Takes
+    /// effect for the radio after the next `config_commit`.
+    fn set_raw_fcs(&self, enabled: bool);
+    /// Whether raw frames include their FCS
+    fn is_raw_fcs(&self) -> bool;
+    /// Act as the PAN coordinator, accepting frames without a destination
+    /// address as addressed to this device. Takes effect for the radio after
+    /// the next `config_commit`.
+    fn set_pan_coordinator(&self, coordinator: bool);
+    /// Whether this device acts as the PAN coordinator
+    fn is_pan_coordinator(&self) -> bool;
+    /// Never transmit, refusing all transmissions and, after the next
+    /// `config_commit`, not acknowledging received frames either.
+    fn set_listen_only(&self, listen_only: bool);
+    /// Whether this device is in listen-only mode
+    fn is_listen_only(&self) -> bool;
+    /// Receive every frame on the channel regardless of its destination, and
+    /// acknowledge none of them. Takes effect for the radio after the next
+    /// `config_commit`.
+    fn set_promiscuous(&self, promiscuous: bool);
+    /// Whether this device is in promiscuous mode
+    fn is_promiscuous(&self) -> bool;
+    /// Let the radio acknowledge received frames that request it. Takes
+    /// effect for the radio after the next `config_commit`.
+    fn set_auto_ack(&self, auto_ack: bool);
+    /// Whether received frames are acknowledged, which they never are in
+    /// listen-only or promiscuous mode
+    fn is_auto_ack(&self) -> bool;
+    /// Keep the radio's receiver on while it is idle. Takes effect for the
+    /// radio after the next `config_commit`.
+    fn set_rx_on_when_idle(&self, rx_on: bool);
+    /// Whether the receiver stays on while the radio is idle
+    fn is_rx_on_when_idle(&self) -> bool;
+    /// Set the radio's CSMA-CA backoff exponents and number of retries.
+    /// Takes effect after the next `config_commit`.
+    fn set_csma_params(&self, min_be: u8, max_be: u8, max_retries: u8) -> ReturnCode;
+    /// Select the radio's receiver sensitivity mode. Takes effect after the
+    /// next `config_commit`.
+    fn set_sensitivity_mode(&self, mode: radio::SensitivityMode) -> ReturnCode;
+    /// Loop transmitted frames back to the receiver, if the radio supports
+    /// it. Returns ENOSUPPORT otherwise.
+    fn set_loopback(&self, loopback: bool) -> ReturnCode;
+    /// Retransmit frames that were corrupted by a frame buffer underrun in the
+    /// radio, a few times, before reporting the failure to the client
+    fn set_underrun_retry(&self, retry: bool);
+    /// The number of transmissions corrupted by a frame buffer underrun,
+    /// including retransmissions
+    fn get_underrun_count(&self) -> u32;
+    /// Retransmit unicast frames that were not acknowledged up to `retries`
+    /// times, on top of any retransmissions the radio performs itself, before
+    /// reporting the failure to the client. Disabled (0) by default.
+    fn set_tx_retries(&self, retries: u8);
+    /// The number of times the most recently completed transmission was
+    /// retransmitted because it was not acknowledged
+    fn get_tx_retries_used(&self) -> u8;
+    /// Enable or disable incrementing the data sequence number after each
+    /// transmission. While disabled, every frame carries the value last set
+    /// with `set_data_sequence`. Enabled by default.
+    fn set_data_sequence_increment(&self, increment: bool);
+    /// The data sequence number carried by the most recently completed
+    /// transmission, or `None` if no frame has been transmitted yet
+    fn get_last_tx_sequence(&self) -> Option<u8>;
 
     /// This method must be called after one or more calls to `set_*`. If
     /// `set_*` is called without calling `config_commit`, there is no guarantee
@@ -252,6 +397,46 @@ pub trait Mac<'a> {
 
     /// Returns if the MAC device is currently on.
     fn is_on(&self) -> bool;
+    /// Turns the radio back on after `stop`. Returns EALREADY if it is
+    /// already on.
+    fn start(&self) -> ReturnCode;
+    /// Puts the radio to sleep. Returns EALREADY if it is already off and
+    /// EBUSY while it is transmitting or receiving a frame.
+    fn stop(&self) -> ReturnCode;
+
+    /// Returns the number of frames received with an invalid FCS on a channel
+    /// since the counts were last reset, or `None` if the channel is invalid.
+    /// Frames are counted against the channel the radio was configured with
+    /// when they arrived.
+    fn get_crc_error_count(&self, channel: u8) -> Option<u32>;
+    /// Resets the counts of frames received with an invalid FCS on every
+    /// channel.
+    fn reset_crc_error_counts(&self);
+
+    /// Returns if the underlying radio holds a buffer to receive frames into.
+    /// This is false while a received frame is being processed.
+    fn has_receive_buffer(&self) -> bool;
+    /// Returns the received signal strength of the most recently received
+    /// frame in dBm. This is valid for the frame passed to
+    /// `RxClient::receive`.
+    fn get_rx_rssi(&self) -> i8;
+    /// Returns the link quality indication of the most recently received
+    /// frame, or `radio::LQI_UNKNOWN` if the radio could not determine it.
+    fn get_rx_lqi(&self) -> u8;
+    /// Pass frames with an invalid FCS to the receive client instead of
+    /// dropping them. Such frames are still counted as CRC errors.
+    fn set_deliver_crc_errors(&self, deliver: bool);
+    /// Returns whether the FCS of the most recently received frame was valid.
+    /// This is valid for the frame passed to `RxClient::receive`, and only
+    /// ever false if CRC errors are delivered.
+    fn get_rx_crc_valid(&self) -> bool;
+    /// The number of buffers the radio receives into, which is 2 if a spare
+    /// receive buffer was configured and 1 otherwise.
+    fn get_receive_buffer_count(&self) -> usize;
+
+    /// Starts a measurement of the received signal strength on the current
+    /// channel. The result is returned through `MeasurementClient#rssi_done`.
+    fn read_rssi(&self) -> ReturnCode;
 
     /// Prepares a mutable buffer slice as an 802.15.4 frame by writing the appropriate
     /// header bytes into the buffer. This needs to be done before adding the
@@ -277,10 +462,44 @@ pub trait Mac<'a> {
                           security_needed: Option<(SecurityLevel, KeyId)>)
                           -> Result<Frame, &'static mut [u8]>;
 
+    /// Like `prepare_data_frame`, but also inserts the given information
+    /// elements into the header. Header IEs are placed after the auxiliary
+    /// security header and payload IEs at the start of the MAC payload, with
+    /// the required list terminators. IEs can only be sent in 802.15.4-2015
+    /// frames, so this fails if any are given under an older frame version.
+    fn prepare_data_frame_with_ies<'b>(&self,
+                                       buf: &'static mut [u8],
+                                       dst_pan: PanID,
+                                       dst_addr: MacAddress,
+                                       src_pan: PanID,
+                                       src_addr: MacAddress,
+                                       security_needed: Option<(SecurityLevel, KeyId)>,
+                                       header_ies: &[HeaderIE<'b>],
+                                       payload_ies: &[PayloadIE<'b>])
+                                       -> Result<Frame, &'static mut [u8]>;
+
+    /// Wraps a complete, pre-formed frame, MAC header included, so that it is
+    /// transmitted verbatim. The frame ends in its FCS if `set_raw_fcs` is
+    /// enabled, and otherwise the FCS is appended to it. Neither the header nor
+    /// the sequence number are touched, and no security is applied. Fails if
+    /// the frame is shorter than its frame control field, has a reserved
+    /// frame type, or does not fit into `buf` or a PSDU.
+    fn prepare_raw_frame(&self,
+                         buf: &'static mut [u8],
+                         frame: &[u8])
+                         -> Result<Frame, &'static mut [u8]>;
+
     /// Transmits a frame that has been prepared by the above process. If the
     /// transmission process fails, the buffer inside the frame is returned so
     /// that it can be re-used.
     fn transmit(&self, frame: Frame) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Stops the transmission of the frame passed to `transmit`, including
+    /// any retransmissions, unless the radio has already sent it, and returns
+    /// its buffer. No `send_done` callback follows for an aborted frame.
+    /// Returns EALREADY if no frame is being transmitted, and EBUSY if it can
+    /// no longer be stopped, in which case it completes as usual.
+    fn abort_transmit(&self) -> (ReturnCode, Option<&'static mut [u8]>);
 }
 
 /// Trait to be implemented by any user of the IEEE 802.15.4 device that
@@ -321,6 +540,31 @@ pub trait RxClient {
     fn receive<'a>(&self, buf: &'a [u8], header: Header<'a>, data_offset: usize, data_len: usize);
 }
 
+/// Trait to be implemented by users of the IEEE 802.15.4 device that request
+/// measurements of the radio channel.
+pub trait MeasurementClient {
+    /// When a received signal strength measurement completes, this callback is
+    /// triggered with the measured `rssi` in dBm. `result` indicates whether or
+    /// not the measurement was successful.
+    fn rssi_done(&self, rssi: i8, result: ReturnCode);
+}
+
+/// Trait to be implemented by users of the IEEE 802.15.4 device that want to
+/// know when the radio is ready to send and receive frames.
+pub trait PowerClient {
+    /// Triggered when the radio finishes powering up (`on` is true) or goes to
+    /// sleep (`on` is false).
+    fn changed(&self, on: bool);
+}
+
+/// Trait to be implemented by users of the IEEE 802.15.4 device that want to
+/// know when configuration changes have taken effect in the radio.
+pub trait ConfigClient {
+    /// Triggered once the radio has applied the configuration committed by
+    /// `config_commit`.
+    fn config_done(&self, result: ReturnCode);
+}
+
 /// IEEE 802.15.4-2015, 9.2.2, KeyDescriptor lookup procedure.
 /// Trait to be implemented by an upper layer that manages the list of 802.15.4
 /// key descriptors. This trait interface enables the lookup procedure to be
@@ -395,6 +639,31 @@ enum RxState {
 pub struct MacDevice<'a, R: radio::Radio + 'a> {
     radio: &'a R,
     data_sequence: Cell<u8>,
+    data_sequence_increment: Cell<bool>,
+    last_tx_sequence: Cell<Option<u8>>,
+    frame_version: Cell<FrameVersion>,
+    software_crc: Cell<bool>,
+    raw_fcs: Cell<bool>,
+    pan_coordinator: Cell<bool>,
+    listen_only: Cell<bool>,
+    promiscuous: Cell<bool>,
+    auto_ack: Cell<bool>,
+    rx_on_when_idle: Cell<bool>,
+    underrun_retry: Cell<bool>,
+    underrun_attempts: Cell<u8>,
+    underrun_count: Cell<u32>,
+    tx_retries: Cell<u8>,
+    tx_retry_attempts: Cell<u8>,
+    tx_retries_used: Cell<u8>,
+    /// Length of the frame most recently passed to the radio, to retransmit
+    /// it after an underrun
+    tx_len: Cell<usize>,
+    /// Number of frames received with an invalid FCS on each channel
+    crc_errors: Cell<[u32; NUM_CHANNELS]>,
+    /// Whether frames with an invalid FCS are passed to the receive client
+    deliver_crc_errors: Cell<bool>,
+    /// Whether the FCS of the frame being received was valid
+    rx_crc_valid: Cell<bool>,
 
     /// KeyDescriptor lookup procedure
     key_procedure: Cell<Option<&'a KeyProcedure>>,
@@ -412,6 +681,15 @@ pub struct MacDevice<'a, R: radio::Radio + 'a> {
     /// `None`, except when transitioning between states.
     rx_state: MapCell<RxState>,
     rx_client: Cell<Option<&'a RxClient>>,
+    /// Second receive buffer, if configured, that is handed to the radio as
+    /// soon as a frame arrives so that it can receive the next frame while
+    /// this one is still being processed.
+    rx_spare: TakeCell<'static, [u8]>,
+    rx_spare_configured: Cell<bool>,
+
+    measurement_client: Cell<Option<&'a MeasurementClient>>,
+    power_client: Cell<Option<&'a PowerClient>>,
+    config_client: Cell<Option<&'a ConfigClient>>,
 }
 
 impl<'a, R: radio::Radio + 'a> MacDevice<'a, R> {
@@ -419,12 +697,56 @@ impl<'a, R: radio::Radio + 'a> MacDevice<'a, R> {
         MacDevice {
             radio: radio,
             data_sequence: Cell::new(0),
+            data_sequence_increment: Cell::new(true),
+            last_tx_sequence: Cell::new(None),
+            frame_version: Cell::new(FrameVersion::V2015),
+            software_crc: Cell::new(false),
+            raw_fcs: Cell::new(false),
+            pan_coordinator: Cell::new(false),
+            listen_only: Cell::new(false),
+            promiscuous: Cell::new(true),
+            auto_ack: Cell::new(true),
+            rx_on_when_idle: Cell::new(true),
+            underrun_retry: Cell::new(false),
+            underrun_attempts: Cell::new(0),
+            underrun_count: Cell::new(0),
+            tx_retries: Cell::new(0),
+            tx_retry_attempts: Cell::new(0),
+            tx_retries_used: Cell::new(0),
+            tx_len: Cell::new(0),
+            crc_errors: Cell::new([0; NUM_CHANNELS]),
+            deliver_crc_errors: Cell::new(false),
+            rx_crc_valid: Cell::new(true),
             key_procedure: Cell::new(None),
             device_procedure: Cell::new(None),
             tx_state: MapCell::new(TxState::Idle),
             tx_client: Cell::new(None),
             rx_state: MapCell::new(RxState::Idle),
             rx_client: Cell::new(None),
+            rx_spare: TakeCell::empty(),
+            rx_spare_configured: Cell::new(false),
+            measurement_client: Cell::new(None),
+            power_client: Cell::new(None),
+            config_client: Cell::new(None),
+        }
+    }
+
+    /// Adds a second receive buffer, the same size as the one given to the
+    /// radio. While a received frame is being processed, for instance while
+    /// it is being decrypted, the radio receives into the other buffer, so
+    /// that back-to-back frames are not dropped.
+    pub fn set_spare_receive_buffer(&self, buf: &'static mut [u8]) {
+        self.rx_spare_configured.set(true);
+        self.return_receive_buffer(buf);
+    }
+
+    /// Gives a receive buffer that is no longer in use back to the radio, or
+    /// keeps it as the spare if the radio already has one.
+    fn return_receive_buffer(&self, buf: &'static mut [u8]) {
+        if self.radio.has_receive_buffer() {
+            self.rx_spare.replace(buf);
+        } else {
+            self.radio.set_receive_buffer(buf);
         }
     }
 
@@ -498,6 +820,10 @@ impl<'a, R: radio::Radio + 'a> MacDevice<'a, R> {
                 // This is fine because we re-parse the unsecured frame before
                 // exposing it to the user. At that time, the data payload field
                 // will not include the payload IEs.
+                if (header.version as u16) > (self.frame_version.get() as u16) {
+                    // Drop frames newer than the configured frame version
+                    return None;
+                }
                 let mic_len = header.security.map_or(0, |sec| sec.level.mic_len());
                 let data_len = frame_len - data_offset - mic_len;
                 if let Some(security) = header.security {
@@ -551,6 +877,7 @@ impl<'a, R: radio::Radio + 'a> MacDevice<'a, R> {
                             data_offset: data_offset,
                             data_len: data_len,
                             mic_len: mic_len,
+                            fcs_included: false,
                             security_params: Some((security.level, key, nonce)),
                         })
                     }
@@ -608,7 +935,16 @@ impl<'a, R: radio::Radio + 'a> MacDevice<'a, R> {
                         (TxState::Encrypting(info), (ReturnCode::SUCCESS, None))
                     }
                     TxState::ReadyToTransmit(info, buf) => {
-                        let (rval, buf) = self.radio.transmit(buf, info.secured_length());
+                        let frame_len = info.secured_length();
+                        let fcs_offset = radio::PSDU_OFFSET + frame_len;
+                        if (self.software_crc.get() || self.raw_fcs.get()) && !info.fcs_included &&
+                           fcs_offset + radio::MFR_SIZE <= buf.len() {
+                            let fcs = frame_check_sequence(&buf[radio::PSDU_OFFSET..fcs_offset]);
+                            buf[fcs_offset] = fcs as u8;
+                            buf[fcs_offset + 1] = (fcs >> 8) as u8;
+                        }
+                        self.tx_len.set(frame_len);
+                        let (rval, buf) = self.radio.transmit(buf, frame_len);
                         match rval {
                             // If the radio is busy, just wait for either a
                             // transmit_done or config_done callback to trigger
@@ -701,7 +1037,7 @@ impl<'a, R: radio::Radio + 'a> MacDevice<'a, R> {
 
                 // Return the buffer to the radio if we are done with it.
                 if let Some(buf) = buf {
-                    self.radio.set_receive_buffer(buf);
+                    self.return_receive_buffer(buf);
                 }
             });
     }
@@ -716,6 +1052,18 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
         self.rx_client.set(Some(client));
     }
 
+    fn set_measurement_client(&self, client: &'a MeasurementClient) {
+        self.measurement_client.set(Some(client));
+    }
+
+    fn set_power_client(&self, client: &'a PowerClient) {
+        self.power_client.set(Some(client));
+    }
+
+    fn set_config_client(&self, client: &'a ConfigClient) {
+        self.config_client.set(Some(client));
+    }
+
     fn get_address(&self) -> u16 {
         self.radio.get_address()
     }
@@ -724,6 +1072,14 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
         self.radio.get_address_long()
     }
 
+    fn get_eui64(&self) -> Option<[u8; 8]> {
+        self.radio.get_eui64()
+    }
+
+    fn get_chip_id(&self) -> Option<u32> {
+        self.radio.get_chip_id()
+    }
+
     fn get_pan(&self) -> u16 {
         self.radio.get_pan()
     }
@@ -736,6 +1092,37 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
         self.radio.get_tx_power()
     }
 
+    fn get_symbol_rate(&self) -> u32 {
+        self.radio.get_symbol_rate()
+    }
+
+    fn get_max_retries(&self) -> u8 {
+        // The radio repeats its own retransmissions for each of ours
+        let radio_attempts = self.radio.get_max_retries() as u32 + 1;
+        let attempts = radio_attempts * (self.tx_retries.get() as u32 + 1);
+        min(attempts - 1, u8::max_value() as u32) as u8
+    }
+
+    fn get_capabilities(&self) -> u32 {
+        self.radio.get_capabilities()
+    }
+
+    fn get_last_cca(&self) -> Option<bool> {
+        self.radio.get_last_cca()
+    }
+
+    fn get_ack_frame_pending(&self) -> bool {
+        self.radio.get_ack_frame_pending()
+    }
+
+    fn get_power_state(&self) -> radio::PowerState {
+        self.radio.get_power_state()
+    }
+
+    fn get_frame_version(&self) -> FrameVersion {
+        self.frame_version.get()
+    }
+
     fn set_address(&self, addr: u16) {
         self.radio.set_address(addr)
     }
@@ -756,6 +1143,113 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
         self.radio.set_tx_power(power)
     }
 
+    fn set_data_sequence(&self, seq: u8) {
+        self.data_sequence.set(seq)
+    }
+
+    fn set_frame_version(&self, version: FrameVersion) {
+        self.frame_version.set(version)
+    }
+
+    fn set_software_crc(&self, enabled: bool) {
+        self.software_crc.set(enabled);
+        self.radio.set_auto_crc(!enabled && !self.raw_fcs.get());
+    }
+
+    fn is_software_crc(&self) -> bool {
+        self.software_crc.get()
+    }
+
+    fn set_raw_fcs(&self, enabled: bool) {
+        self.raw_fcs.set(enabled);
+        self.radio.set_auto_crc(!enabled && !self.software_crc.get());
+    }
+
+    fn is_raw_fcs(&self) -> bool {
+        self.raw_fcs.get()
+    }
+
+    fn set_pan_coordinator(&self, coordinator: bool) {
+        self.pan_coordinator.set(coordinator);
+        self.radio.set_pan_coordinator(coordinator);
+    }
+
+    fn is_pan_coordinator(&self) -> bool {
+        self.pan_coordinator.get()
+    }
+
+    fn set_listen_only(&self, listen_only: bool) {
+        self.listen_only.set(listen_only);
+        self.radio.set_listen_only(listen_only);
+    }
+
+    fn is_listen_only(&self) -> bool {
+        self.listen_only.get()
+    }
+
+    fn set_promiscuous(&self, promiscuous: bool) {
+        self.promiscuous.set(promiscuous);
+        self.radio.set_promiscuous(promiscuous);
+    }
+
+    fn is_promiscuous(&self) -> bool {
+        self.promiscuous.get()
+    }
+
+    fn set_auto_ack(&self, auto_ack: bool) {
+        self.auto_ack.set(auto_ack);
+        self.radio.set_auto_ack(auto_ack);
+    }
+
+    fn is_auto_ack(&self) -> bool {
+        self.auto_ack.get() && !self.listen_only.get() && !self.promiscuous.get()
+    }
+
+    fn set_rx_on_when_idle(&self, rx_on: bool) {
+        self.rx_on_when_idle.set(rx_on);
+        self.radio.set_rx_on_when_idle(rx_on);
+    }
+
+    fn is_rx_on_when_idle(&self) -> bool {
+        self.rx_on_when_idle.get()
+    }
+
+    fn set_csma_params(&self, min_be: u8, max_be: u8, max_retries: u8) -> ReturnCode {
+        self.radio.set_csma_params(min_be, max_be, max_retries)
+    }
+
+    fn set_sensitivity_mode(&self, mode: radio::SensitivityMode) -> ReturnCode {
+        self.radio.set_sensitivity_mode(mode)
+    }
+
+    fn set_loopback(&self, loopback: bool) -> ReturnCode {
+        self.radio.set_loopback(loopback)
+    }
+
+    fn set_underrun_retry(&self, retry: bool) {
+        self.underrun_retry.set(retry);
+    }
+
+    fn get_underrun_count(&self) -> u32 {
+        self.underrun_count.get()
+    }
+
+    fn set_tx_retries(&self, retries: u8) {
+        self.tx_retries.set(retries);
+    }
+
+    fn get_tx_retries_used(&self) -> u8 {
+        self.tx_retries_used.get()
+    }
+
+    fn set_data_sequence_increment(&self, increment: bool) {
+        self.data_sequence_increment.set(increment);
+    }
+
+    fn get_last_tx_sequence(&self) -> Option<u8> {
+        self.last_tx_sequence.get()
+    }
+
     fn config_commit(&self) {
         self.radio.config_commit()
     }
@@ -764,6 +1258,50 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
         self.radio.is_on()
     }
 
+    fn start(&self) -> ReturnCode {
+        self.radio.start()
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.radio.stop()
+    }
+
+    fn get_crc_error_count(&self, channel: u8) -> Option<u32> {
+        channel_index(channel).map(|index| self.crc_errors.get()[index])
+    }
+
+    fn reset_crc_error_counts(&self) {
+        self.crc_errors.set([0; NUM_CHANNELS]);
+    }
+
+    fn has_receive_buffer(&self) -> bool {
+        self.radio.has_receive_buffer()
+    }
+
+    fn get_rx_rssi(&self) -> i8 {
+        self.radio.get_rx_rssi()
+    }
+
+    fn get_rx_lqi(&self) -> u8 {
+        self.radio.get_rx_lqi()
+    }
+
+    fn set_deliver_crc_errors(&self, deliver: bool) {
+        self.deliver_crc_errors.set(deliver);
+    }
+
+    fn get_rx_crc_valid(&self) -> bool {
+        self.rx_crc_valid.get()
+    }
+
+    fn get_receive_buffer_count(&self) -> usize {
+        if self.rx_spare_configured.get() { 2 } else { 1 }
+    }
+
+    fn read_rssi(&self) -> ReturnCode {
+        self.radio.read_rssi()
+    }
+
     fn prepare_data_frame(&self,
                           buf: &'static mut [u8],
                           dst_pan: PanID,
@@ -772,8 +1310,47 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
                           src_addr: MacAddress,
                           security_needed: Option<(SecurityLevel, KeyId)>)
                           -> Result<Frame, &'static mut [u8]> {
+        self.prepare_data_frame_with_ies(buf,
+                                         dst_pan,
+                                         dst_addr,
+                                         src_pan,
+                                         src_addr,
+                                         security_needed,
+                                         &[],
+                                         &[])
+    }
+
+    fn prepare_data_frame_with_ies<'b>(&self,
+                                       buf: &'static mut [u8],
+                                       dst_pan: PanID,
+                                       dst_addr: MacAddress,
+                                       src_pan: PanID,
+                                       src_addr: MacAddress,
+                                       security_needed: Option<(SecurityLevel, KeyId)>,
+                                       header_ies: &[HeaderIE<'b>],
+                                       payload_ies: &[PayloadIE<'b>])
+                                       -> Result<Frame, &'static mut [u8]> {
         // IEEE 802.15.4-2015: 9.2.1, outgoing frame security
         // Steps a-e of the security procedure are implemented here.
+        let version = self.frame_version.get();
+        if security_needed.is_some() && version == FrameVersion::V2003 {
+            // The 2003 security header is not compatible with the one we
+            // encode, so secured frames need a newer frame version.
+            return Err(buf);
+        }
+
+        // Information elements were introduced in 802.15.4-2015
+        let has_ies = header_ies.len() != 0 || payload_ies.len() != 0;
+        if has_ies && version != FrameVersion::V2015 {
+            return Err(buf);
+        }
+        if header_ies.len() > MAX_HEADER_IES || payload_ies.len() > MAX_PAYLOAD_IES {
+            return Err(buf);
+        }
+        let mut header_ies_arr = [HeaderIE::default(); MAX_HEADER_IES];
+        header_ies_arr[..header_ies.len()].copy_from_slice(header_ies);
+        let mut payload_ies_arr = [PayloadIE::default(); MAX_PAYLOAD_IES];
+        payload_ies_arr[..payload_ies.len()].copy_from_slice(payload_ies);
 
         // TODO: For Thread, in the case of `KeyIdMode::Source4Index`, the source
         // address should instead be some constant defined in their
@@ -807,19 +1384,20 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
             /* TODO: determine this by looking at queue, and also set it in
              * hardware so that ACKs set this flag to the right value. */
             frame_pending: false,
-            // Unicast data frames request acknowledgement
-            ack_requested: true,
-            version: FrameVersion::V2015,
+            // Unicast data frames request acknowledgement, but nobody
+            // acknowledges a broadcast
+            ack_requested: dst_addr != MacAddress::Short(0xffff),
+            version: version,
             seq: Some(self.data_sequence.get()),
             dst_pan: Some(dst_pan),
             dst_addr: Some(dst_addr),
             src_pan: Some(src_pan),
             src_addr: Some(src_addr),
             security: security,
-            header_ies: Default::default(),
-            header_ies_len: 0,
-            payload_ies: Default::default(),
-            payload_ies_len: 0,
+            header_ies: header_ies_arr,
+            header_ies_len: header_ies.len(),
+            payload_ies: payload_ies_arr,
+            payload_ies_len: payload_ies.len(),
         };
 
         match header.encode(&mut buf[radio::PSDU_OFFSET..], true).done() {
@@ -832,6 +1410,7 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
                         data_offset: data_offset,
                         data_len: 0,
                         mic_len: mic_len,
+                        fcs_included: false,
                         security_params:
                             security_desc.map(|(sec, key, nonce)| (sec.level, key, nonce)),
                     },
@@ -841,6 +1420,43 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
         }
     }
 
+    fn prepare_raw_frame(&self,
+                         buf: &'static mut [u8],
+                         frame: &[u8])
+                         -> Result<Frame, &'static mut [u8]> {
+        // A frame that brings its own FCS is only as long as what precedes it
+        let fcs_len = if self.raw_fcs.get() { radio::MFR_SIZE } else { 0 };
+        if frame.len() < 2 + fcs_len {
+            return Err(buf);
+        }
+        let frame_len = frame.len() - fcs_len;
+        if frame_len + radio::MFR_SIZE > radio::MAX_FRAME_SIZE ||
+           radio::PSDU_OFFSET + frame_len + radio::MFR_SIZE > buf.len() {
+            return Err(buf);
+        }
+        let fcf = (frame[0] as u16) | ((frame[1] as u16) << 8);
+        let frame_type = match FrameType::from_fcf(fcf) {
+            Some(frame_type) => frame_type,
+            None => {
+                return Err(buf);
+            }
+        };
+        buf[radio::PSDU_OFFSET..radio::PSDU_OFFSET + frame.len()].copy_from_slice(frame);
+        // Treat the whole frame as the header so that nothing is appended
+        Ok(Frame {
+            buf: buf,
+            info: FrameInfo {
+                frame_type: frame_type,
+                mac_payload_offset: frame_len,
+                data_offset: frame_len,
+                data_len: 0,
+                mic_len: 0,
+                fcs_included: fcs_len != 0,
+                security_params: None,
+            },
+        })
+    }
+
     fn transmit(&self, frame: Frame) -> (ReturnCode, Option<&'static mut [u8]>) {
         let Frame { buf, info } = frame;
         let state = match self.tx_state.take() {
@@ -861,22 +1477,106 @@ impl<'a, R: radio::Radio + 'a> Mac<'a> for MacDevice<'a, R> {
             }
         }
     }
+
+    fn abort_transmit(&self) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let state = match self.tx_state.take() {
+            None => {
+                return (ReturnCode::FAIL, None);
+            }
+            Some(state) => state,
+        };
+        let (next_state, result) = match state {
+            TxState::Idle => (TxState::Idle, self.radio.abort_transmit()),
+            TxState::ReadyToEncrypt(_, buf) |
+            TxState::ReadyToTransmit(_, buf) => (TxState::Idle, (ReturnCode::SUCCESS, Some(buf))),
+            // The buffer is with the encryption hardware
+            TxState::Encrypting(info) => (TxState::Encrypting(info), (ReturnCode::EBUSY, None)),
+        };
+        self.tx_state.replace(next_state);
+        if result.0 == ReturnCode::SUCCESS {
+            self.tx_retry_attempts.set(0);
+            self.underrun_attempts.set(0);
+        }
+        result
+    }
 }
 
 impl<'a, R: radio::Radio + 'a> radio::TxClient for MacDevice<'a, R> {
-    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode) {
-        self.data_sequence.set(self.data_sequence.get() + 1);
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, underrun: bool, result: ReturnCode) {
+        let mut result = result;
+        let mut buf = buf;
+        if underrun {
+            self.underrun_count.set(self.underrun_count.get().wrapping_add(1));
+            let attempts = self.underrun_attempts.get();
+            // A corrupted frame that is not retransmitted failed
+            result = ReturnCode::FAIL;
+            if self.underrun_retry.get() && attempts < MAX_UNDERRUN_RETRIES {
+                self.underrun_attempts.set(attempts + 1);
+                match self.radio.transmit(buf, self.tx_len.get()) {
+                    (ReturnCode::SUCCESS, _) => return,
+                    (rval, Some(rbuf)) => {
+                        result = rval;
+                        buf = rbuf;
+                    }
+                    // The radio forgot to return the buffer.
+                    (_, None) => return,
+                }
+            }
+        }
+        // Only retransmit unacknowledged frames that asked to be acknowledged,
+        // which broadcast frames never do
+        let attempts = self.tx_retry_attempts.get();
+        if result == ReturnCode::SUCCESS && !acked && attempts < self.tx_retries.get() &&
+           requests_ack(buf) {
+            self.tx_retry_attempts.set(attempts + 1);
+            match self.radio.transmit(buf, self.tx_len.get()) {
+                (ReturnCode::SUCCESS, _) => return,
+                (rval, Some(rbuf)) => {
+                    result = rval;
+                    buf = rbuf;
+                }
+                // The radio forgot to return the buffer.
+                (_, None) => return,
+            }
+        }
+        self.tx_retries_used.set(self.tx_retry_attempts.get());
+        self.tx_retry_attempts.set(0);
+        self.underrun_attempts.set(0);
+        self.last_tx_sequence.set(Some(self.data_sequence.get()));
+        if self.data_sequence_increment.get() {
+            self.data_sequence.set(self.data_sequence.get().wrapping_add(1));
+        }
         self.tx_client.get().map(move |client| { client.send_done(buf, acked, result); });
     }
 }
 
 impl<'a, R: radio::Radio + 'a> radio::RxClient for MacDevice<'a, R> {
     fn receive(&self, buf: &'static mut [u8], frame_len: usize, crc_valid: bool, _: ReturnCode) {
-        // Drop all frames with invalid CRC
+        // Let the radio receive the next frame right away
+        self.rx_spare.take().map(|spare| self.radio.set_receive_buffer(spare));
+
+        let crc_valid = if self.software_crc.get() {
+            let fcs_offset = radio::PSDU_OFFSET + frame_len;
+            fcs_offset + radio::MFR_SIZE <= buf.len() &&
+            frame_check_sequence(&buf[radio::PSDU_OFFSET..fcs_offset]) ==
+            (buf[fcs_offset] as u16 | (buf[fcs_offset + 1] as u16) << 8)
+        } else {
+            crc_valid
+        };
+
+        // Drop all frames with invalid CRC, unless the client asked for them
         if !crc_valid {
-            self.radio.set_receive_buffer(buf);
-            return;
+            if let Some(index) = channel_index(self.radio.get_channel()) {
+                let mut crc_errors = self.crc_errors.get();
+                crc_errors[index] = crc_errors[index].wrapping_add(1);
+                self.crc_errors.set(crc_errors);
+            }
+            if !self.deliver_crc_errors.get() {
+                self.return_receive_buffer(buf);
+                return;
+            }
         }
+        self.rx_crc_valid.set(crc_valid);
 
         self.rx_state
             .take()
@@ -892,7 +1592,7 @@ impl<'a, R: radio::Radio + 'a> radio::RxClient for MacDevice<'a, R> {
                         // this MAC layer provided a receive buffer to the
                         // radio, but if this occurs then we have no choice but
                         // to drop the frame.
-                        self.radio.set_receive_buffer(buf);
+                        self.return_receive_buffer(buf);
                         other_state
                     }
                 };
@@ -903,7 +1603,7 @@ impl<'a, R: radio::Radio + 'a> radio::RxClient for MacDevice<'a, R> {
 }
 
 impl<'a, R: radio::Radio + 'a> radio::ConfigClient for MacDevice<'a, R> {
-    fn config_done(&self, _: ReturnCode) {
+    fn config_done(&self, result: ReturnCode) {
         // The transmission pipeline is the only state machine that
         // waits for the configuration procedure to complete before
         // advancing.
@@ -912,5 +1612,18 @@ impl<'a, R: radio::Radio + 'a> radio::ConfigClient for MacDevice<'a, R> {
             // Return the buffer to the transmit client
             self.tx_client.get().map(move |client| { client.send_done(buf, false, rval); });
         }
+        self.config_client.get().map(|client| { client.config_done(result); });
+    }
+}
+
+impl<'a, R: radio::Radio + 'a> radio::MeasurementClient for MacDevice<'a, R> {
+    fn rssi_done(&self, rssi: i8, result: ReturnCode) {
+        self.measurement_client.get().map(|client| { client.rssi_done(rssi, result); });
+    }
+}
+
+impl<'a, R: radio::Radio + 'a> radio::PowerClient for MacDevice<'a, R> {
+    fn changed(&self, on: bool) {
+        self.power_client.get().map(|client| { client.changed(on); });
     }
 }
@@ -31,6 +31,7 @@ use ieee802154::mac;
 use kernel::ReturnCode;
 use kernel::common::{List, ListLink, ListNode};
 use kernel::common::take_cell::MapCell;
+use kernel::hil::radio;
 use net::ieee802154::*;
 
 /// IEE 802.15.4 MAC device muxer that keeps a list of MAC users and sequences
@@ -40,6 +41,7 @@ pub struct MuxMac<'a> {
     mac: &'a mac::Mac<'a>,
     users: List<'a, MacUser<'a>>,
     inflight: Cell<Option<&'a MacUser<'a>>>,
+    measuring: Cell<Option<&'a MacUser<'a>>>,
 }
 
 impl<'a> mac::TxClient for MuxMac<'a> {
@@ -54,8 +56,39 @@ impl<'a> mac::TxClient for MuxMac<'a> {
 
 impl<'a> mac::RxClient for MuxMac<'a> {
     fn receive<'b>(&self, buf: &'b [u8], header: Header<'b>, data_offset: usize, data_len: usize) {
+        // Frames with an invalid FCS only go to the users that asked for them
+        let crc_valid = self.mac.get_rx_crc_valid();
         for user in self.users.iter() {
-            user.receive(buf, header, data_offset, data_len);
+            if crc_valid || user.deliver_crc_errors.get() {
+                user.receive(buf, header, data_offset, data_len);
+            }
+        }
+    }
+}
+
+impl<'a> mac::MeasurementClient for MuxMac<'a> {
+    fn rssi_done(&self, rssi: i8, result: ReturnCode) {
+        self.measuring.get().map(move |user| {
+            self.measuring.set(None);
+            user.rssi_done(rssi, result);
+        });
+    }
+}
+
+impl<'a> mac::PowerClient for MuxMac<'a> {
+    fn changed(&self, on: bool) {
+        // Every user shares the one radio, so they all hear about it
+        for user in self.users.iter() {
+            user.power_changed(on);
+        }
+    }
+}
+
+impl<'a> mac::ConfigClient for MuxMac<'a> {
+    fn config_done(&self, result: ReturnCode) {
+        // The configuration is shared as well
+        for user in self.users.iter() {
+            user.config_done(result);
         }
     }
 }
@@ -66,7 +99,54 @@ impl<'a> MuxMac<'a> {
             mac: mac,
             users: List::new(),
             inflight: Cell::new(None),
+            measuring: Cell::new(None),
+        }
+    }
+
+    /// Starts a signal strength measurement on behalf of `user`. Only one
+    /// measurement can be outstanding at a time across all users. As in
+    /// `do_next_op_sync`, the raw pointer comparison is only used to find the
+    /// registered user, and is never dereferenced.
+    fn read_rssi(&self, user: &MacUser<'a>) -> ReturnCode {
+        if self.measuring.get().is_some() {
+            return ReturnCode::EBUSY;
         }
+        let node = match self.users.iter().find(|node| *node as *const _ == user as *const _) {
+            Some(node) => node,
+            None => {
+                return ReturnCode::EINVAL;
+            }
+        };
+        let result = self.mac.read_rssi();
+        if result == ReturnCode::SUCCESS {
+            self.measuring.set(Some(node));
+        }
+        result
+    }
+
+    /// Aborts the transmission of `user` if it is the one in flight, and then
+    /// begins the next outstanding transmission. As in `do_next_op_sync`, the
+    /// raw pointer comparison is never dereferenced.
+    fn abort_transmit(&self, user: &MacUser<'a>) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let is_inflight = self.inflight
+            .get()
+            .map_or(false, |node| node as *const _ == user as *const _);
+        if !is_inflight {
+            return (ReturnCode::EALREADY, None);
+        }
+        let result = self.mac.abort_transmit();
+        if result.0 == ReturnCode::SUCCESS {
+            self.inflight.set(None);
+            self.do_next_op_async();
+        }
+        result
+    }
+
+    /// Passes frames with an invalid FCS up from the MAC device as long as at
+    /// least one user wants to receive them.
+    fn update_deliver_crc_errors(&self) {
+        let deliver = self.users.iter().any(|user| user.deliver_crc_errors.get());
+        self.mac.set_deliver_crc_errors(deliver);
     }
 
     /// Registers a MAC user with this MAC mux device. Each MAC user should only
@@ -184,6 +264,10 @@ pub struct MacUser<'a> {
     next: ListLink<'a, MacUser<'a>>,
     tx_client: Cell<Option<&'a mac::TxClient>>,
     rx_client: Cell<Option<&'a mac::RxClient>>,
+    measurement_client: Cell<Option<&'a mac::MeasurementClient>>,
+    power_client: Cell<Option<&'a mac::PowerClient>>,
+    config_client: Cell<Option<&'a mac::ConfigClient>>,
+    deliver_crc_errors: Cell<bool>,
 }
 
 impl<'a> MacUser<'a> {
@@ -194,6 +278,10 @@ impl<'a> MacUser<'a> {
             next: ListLink::empty(),
             tx_client: Cell::new(None),
             rx_client: Cell::new(None),
+            measurement_client: Cell::new(None),
+            power_client: Cell::new(None),
+            config_client: Cell::new(None),
+            deliver_crc_errors: Cell::new(false),
         }
     }
 }
@@ -210,6 +298,20 @@ impl<'a> MacUser<'a> {
             .get()
             .map(move |client| client.receive(buf, header, data_offset, data_len));
     }
+
+    fn rssi_done(&self, rssi: i8, result: ReturnCode) {
+        self.measurement_client
+            .get()
+            .map(move |client| client.rssi_done(rssi, result));
+    }
+
+    fn power_changed(&self, on: bool) {
+        self.power_client.get().map(move |client| client.changed(on));
+    }
+
+    fn config_done(&self, result: ReturnCode) {
+        self.config_client.get().map(move |client| client.config_done(result));
+    }
 }
 
 impl<'a> ListNode<'a, MacUser<'a>> for MacUser<'a> {
@@ -227,6 +329,18 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
         self.rx_client.set(Some(client));
     }
 
+    fn set_measurement_client(&self, client: &'a mac::MeasurementClient) {
+        self.measurement_client.set(Some(client));
+    }
+
+    fn set_power_client(&self, client: &'a mac::PowerClient) {
+        self.power_client.set(Some(client));
+    }
+
+    fn set_config_client(&self, client: &'a mac::ConfigClient) {
+        self.config_client.set(Some(client));
+    }
+
     fn get_address(&self) -> u16 {
         self.mux.mac.get_address()
     }
@@ -235,6 +349,14 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
         self.mux.mac.get_address_long()
     }
 
+    fn get_eui64(&self) -> Option<[u8; 8]> {
+        self.mux.mac.get_eui64()
+    }
+
+    fn get_chip_id(&self) -> Option<u32> {
+        self.mux.mac.get_chip_id()
+    }
+
     fn get_pan(&self) -> u16 {
         self.mux.mac.get_pan()
     }
@@ -247,6 +369,34 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
         self.mux.mac.get_tx_power()
     }
 
+    fn get_symbol_rate(&self) -> u32 {
+        self.mux.mac.get_symbol_rate()
+    }
+
+    fn get_max_retries(&self) -> u8 {
+        self.mux.mac.get_max_retries()
+    }
+
+    fn get_capabilities(&self) -> u32 {
+        self.mux.mac.get_capabilities()
+    }
+
+    fn get_last_cca(&self) -> Option<bool> {
+        self.mux.mac.get_last_cca()
+    }
+
+    fn get_ack_frame_pending(&self) -> bool {
+        self.mux.mac.get_ack_frame_pending()
+    }
+
+    fn get_power_state(&self) -> radio::PowerState {
+        self.mux.mac.get_power_state()
+    }
+
+    fn get_frame_version(&self) -> FrameVersion {
+        self.mux.mac.get_frame_version()
+    }
+
     fn set_address(&self, addr: u16) {
         self.mux.mac.set_address(addr)
     }
@@ -267,6 +417,106 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
         self.mux.mac.set_tx_power(power)
     }
 
+    fn set_data_sequence(&self, seq: u8) {
+        self.mux.mac.set_data_sequence(seq)
+    }
+
+    fn set_frame_version(&self, version: FrameVersion) {
+        self.mux.mac.set_frame_version(version)
+    }
+
+    fn set_software_crc(&self, enabled: bool) {
+        self.mux.mac.set_software_crc(enabled)
+    }
+
+    fn is_software_crc(&self) -> bool {
+        self.mux.mac.is_software_crc()
+    }
+
+    fn set_raw_fcs(&self, enabled: bool) {
+        self.mux.mac.set_raw_fcs(enabled)
+    }
+
+    fn is_raw_fcs(&self) -> bool {
+        self.mux.mac.is_raw_fcs()
+    }
+
+    fn set_pan_coordinator(&self, coordinator: bool) {
+        self.mux.mac.set_pan_coordinator(coordinator)
+    }
+
+    fn is_pan_coordinator(&self) -> bool {
+        self.mux.mac.is_pan_coordinator()
+    }
+
+    fn set_listen_only(&self, listen_only: bool) {
+        self.mux.mac.set_listen_only(listen_only)
+    }
+
+    fn is_listen_only(&self) -> bool {
+        self.mux.mac.is_listen_only()
+    }
+
+    fn set_promiscuous(&self, promiscuous: bool) {
+        self.mux.mac.set_promiscuous(promiscuous)
+    }
+
+    fn is_promiscuous(&self) -> bool {
+        self.mux.mac.is_promiscuous()
+    }
+
+    fn set_auto_ack(&self, auto_ack: bool) {
+        self.mux.mac.set_auto_ack(auto_ack)
+    }
+
+    fn is_auto_ack(&self) -> bool {
+        self.mux.mac.is_auto_ack()
+    }
+
+    fn set_rx_on_when_idle(&self, rx_on: bool) {
+        self.mux.mac.set_rx_on_when_idle(rx_on)
+    }
+
+    fn is_rx_on_when_idle(&self) -> bool {
+        self.mux.mac.is_rx_on_when_idle()
+    }
+
+    fn set_csma_params(&self, min_be: u8, max_be: u8, max_retries: u8) -> ReturnCode {
+        self.mux.mac.set_csma_params(min_be, max_be, max_retries)
+    }
+
+    fn set_sensitivity_mode(&self, mode: radio::SensitivityMode) -> ReturnCode {
+        self.mux.mac.set_sensitivity_mode(mode)
+    }
+
+    fn set_loopback(&self, loopback: bool) -> ReturnCode {
+        self.mux.mac.set_loopback(loopback)
+    }
+
+    fn set_underrun_retry(&self, retry: bool) {
+        self.mux.mac.set_underrun_retry(retry)
+    }
+
+    fn get_underrun_count(&self) -> u32 {
+        self.mux.mac.get_underrun_count()
+    }
+
+    fn set_tx_retries(&self, retries: u8) {
+        self.mux.mac.set_tx_retries(retries)
+    }
+
+    fn get_tx_retries_used(&self) -> u8 {
+        self.mux.mac.get_tx_retries_used()
+    }
+
+    fn set_data_sequence_increment(&self, increment: bool) {
+        self.mux.mac.set_data_sequence_increment(increment)
+    }
+
+    fn get_last_tx_sequence(&self) -> Option<u8> {
+        self.mux.mac.get_last_tx_sequence()
+    }
+
     fn config_commit(&self) {
         self.mux.mac.config_commit()
     }
@@ -275,6 +525,51 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
         self.mux.mac.is_on()
     }
 
+    fn start(&self) -> ReturnCode {
+        self.mux.mac.start()
+    }
+
+    fn stop(&self) -> ReturnCode {
+        self.mux.mac.stop()
+    }
+
+    fn get_crc_error_count(&self, channel: u8) -> Option<u32> {
+        self.mux.mac.get_crc_error_count(channel)
+    }
+
+    fn reset_crc_error_counts(&self) {
+        self.mux.mac.reset_crc_error_counts()
+    }
+
+    fn has_receive_buffer(&self) -> bool {
+        self.mux.mac.has_receive_buffer()
+    }
+
+    fn get_rx_rssi(&self) -> i8 {
+        self.mux.mac.get_rx_rssi()
+    }
+
+    fn get_rx_lqi(&self) -> u8 {
+        self.mux.mac.get_rx_lqi()
+    }
+
+    fn set_deliver_crc_errors(&self, deliver: bool) {
+        self.deliver_crc_errors.set(deliver);
+        self.mux.update_deliver_crc_errors();
+    }
+
+    fn get_rx_crc_valid(&self) -> bool {
+        self.mux.mac.get_rx_crc_valid()
+    }
+
+    fn get_receive_buffer_count(&self) -> usize {
+        self.mux.mac.get_receive_buffer_count()
+    }
+
+    fn read_rssi(&self) -> ReturnCode {
+        self.mux.read_rssi(self)
+    }
+
     fn prepare_data_frame(&self,
                           buf: &'static mut [u8],
                           dst_pan: PanID,
@@ -286,6 +581,33 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
         self.mux.mac.prepare_data_frame(buf, dst_pan, dst_addr, src_pan, src_addr, security_needed)
     }
 
+    fn prepare_data_frame_with_ies<'b>(&self,
+                                       buf: &'static mut [u8],
+                                       dst_pan: PanID,
+                                       dst_addr: MacAddress,
+                                       src_pan: PanID,
+                                       src_addr: MacAddress,
+                                       security_needed: Option<(SecurityLevel, KeyId)>,
+                                       header_ies: &[HeaderIE<'b>],
+                                       payload_ies: &[PayloadIE<'b>])
+                                       -> Result<mac::Frame, &'static mut [u8]> {
+        self.mux.mac.prepare_data_frame_with_ies(buf,
+                                                 dst_pan,
+                                                 dst_addr,
+                                                 src_pan,
+                                                 src_addr,
+                                                 security_needed,
+                                                 header_ies,
+                                                 payload_ies)
+    }
+
+    fn prepare_raw_frame(&self,
+                         buf: &'static mut [u8],
+                         frame: &[u8])
+                         -> Result<mac::Frame, &'static mut [u8]> {
+        self.mux.mac.prepare_raw_frame(buf, frame)
+    }
+
     fn transmit(&self, frame: mac::Frame) -> (ReturnCode, Option<&'static mut [u8]>) {
         // If the muxer is idle, immediately transmit the frame, otherwise
         // attempt to queue the transmission request. However, each MAC user can
@@ -306,4 +628,19 @@ impl<'a> mac::Mac<'a> for MacUser<'a> {
                 }
             })
     }
+
+    fn abort_transmit(&self) -> (ReturnCode, Option<&'static mut [u8]>) {
+        // A frame still waiting for the muxer never reached the MAC device
+        match self.operation.take() {
+            None => (ReturnCode::FAIL, None),
+            Some(Op::Transmit(frame)) => {
+                self.operation.replace(Op::Idle);
+                (ReturnCode::SUCCESS, Some(frame.into_buf()))
+            }
+            Some(Op::Idle) => {
+                self.operation.replace(Op::Idle);
+                self.mux.abort_transmit(self)
+            }
+        }
+    }
 }
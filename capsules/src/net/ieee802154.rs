@@ -39,7 +39,25 @@ impl MacAddress {
 
 pub type PanID = u16;
 
-mod frame_control {
+/// Computes the frame check sequence of a MAC frame: the ITU-T CRC-16 over the
+/// MAC header and payload, with the bits of each octet processed least
+/// significant first. The FCS is transmitted little-endian after the payload.
+pub fn frame_check_sequence(frame: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for byte in frame.iter() {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0x8408;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+pub mod frame_control {
     pub const FRAME_TYPE_MASK: u16 = 0b111;
     pub const SECURITY_ENABLED: u16 = 1 << 3;
     pub const FRAME_PENDING: u16 = 1 << 4;
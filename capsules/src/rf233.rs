@@ -20,6 +20,7 @@
 #![allow(unused_parens)]
 
 use core::cell::Cell;
+use core::cmp::min;
 use kernel::ReturnCode;
 use kernel::common::take_cell::TakeCell;
 use kernel::hil::gpio;
@@ -50,6 +51,7 @@ enum InternalState {
     START_IRQMASK_SET,
     START_XAH1_SET,
     START_XAH0_SET,
+    START_CSMA_BE_SET,
     START_PANID0_SET,
     START_PANID1_SET,
     START_IEEE0_SET,
@@ -96,6 +98,13 @@ enum InternalState {
     // because we need to block other operations.
     TX_PENDING,
 
+    // Abandoning a transmission: waiting for an SPI operation of the
+    // transmission to complete, forcing the transceiver off, and returning it
+    // to idle.
+    TX_ABORTING,
+    TX_ABORT_OFF,
+    TX_ABORT_DONE,
+
     // Intermediate states when committing configuration from RAM
     // to the chiP; short address, PAN address, tx power and channel
     CONFIG_SHORT0_SET,
@@ -111,8 +120,23 @@ enum InternalState {
     CONFIG_IEEE6_SET,
     CONFIG_IEEE7_SET,
     CONFIG_POWER_SET,
+    CONFIG_CCA_SET,
+    CONFIG_CTRL1_SET,
+    CONFIG_SEED1_SET,
+    CONFIG_RX_SYN_SET,
+    CONFIG_XAH1_SET,
+    CONFIG_XAH0_SET,
     CONFIG_DONE,
 
+    // Reading the PHY_RSSI register to measure the signal strength
+    RSSI_READ,
+
+    // Turning the transceiver off before putting the radio to sleep, asleep,
+    // and waiting for the radio to wake up again.
+    SLEEP_TRX_OFF,
+    SLEEP,
+    WAKE_STATUS_READ,
+
     // RX is a short-lived state for when software has detected
     // the chip is receiving a packet (by internal state) but has
     // not received the interrupt yet. I.e., the SFD has been
@@ -125,6 +149,7 @@ enum InternalState {
     RX_READING_FRAME, // Reading the packet out of the radio
     RX_READING_FRAME_DONE, // Now read a register to verify FCS
     RX_READING_FRAME_FCS_DONE,
+    RX_READING_FRAME_ED_DONE,
 }
 
 // There are two tricky parts to this capsule: buffer management
@@ -178,6 +203,13 @@ pub struct RF233<'a, S: spi::SpiMasterDevice + 'a> {
     interrupt_handling: Cell<bool>,
     interrupt_pending: Cell<bool>,
     config_pending: Cell<bool>,
+    rssi_pending: Cell<bool>,
+    sleep_pending: Cell<bool>,
+    waking: Cell<bool>,
+    tx_underrun: Cell<bool>,
+    rx_crc_valid: Cell<bool>,
+    rx_rssi: Cell<i8>,
+    rx_lqi: Cell<u8>,
     reset_pin: &'a gpio::Pin,
     sleep_pin: &'a gpio::Pin,
     irq_pin: &'a gpio::Pin,
@@ -190,11 +222,27 @@ pub struct RF233<'a, S: spi::SpiMasterDevice + 'a> {
     rx_client: Cell<Option<&'static radio::RxClient>>,
     cfg_client: Cell<Option<&'static radio::ConfigClient>>,
     power_client: Cell<Option<&'static radio::PowerClient>>,
+    measurement_client: Cell<Option<&'static radio::MeasurementClient>>,
     addr: Cell<u16>,
     addr_long: Cell<[u8; 8]>,
     pan: Cell<u16>,
     tx_power: Cell<i8>,
     channel: Cell<u8>,
+    auto_crc: Cell<bool>,
+    sensitivity: Cell<radio::SensitivityMode>,
+    pan_coordinator: Cell<bool>,
+    listen_only: Cell<bool>,
+    promiscuous: Cell<bool>,
+    auto_ack: Cell<bool>,
+    rx_on_when_idle: Cell<bool>,
+    // Whether the receiver was left on when the radio last became idle
+    rx_listening: Cell<bool>,
+    min_be: Cell<u8>,
+    max_be: Cell<u8>,
+    max_csma_retries: Cell<u8>,
+    last_cca: Cell<Option<bool>>,
+    ack_frame_pending: Cell<bool>,
+    part_num: Cell<Option<u8>>,
     spi_rx: TakeCell<'static, [u8]>,
     spi_tx: TakeCell<'static, [u8]>,
     spi_buf: TakeCell<'static, [u8]>,
@@ -311,9 +359,13 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 if interrupt_included(interrupt, IRQ_0_PLL_LOCK) {
                     self.state.set(InternalState::ON_PLL_SET);
                 }
-            } else if (state == InternalState::TX_TRANSMITTING) &&
-                      interrupt_included(interrupt, IRQ_3_TRX_END) {
-                self.state.set(InternalState::TX_DONE);
+            } else if state == InternalState::TX_TRANSMITTING {
+                if interrupt_included(interrupt, IRQ_6_TRX_UR) {
+                    self.tx_underrun.set(true);
+                }
+                if interrupt_included(interrupt, IRQ_3_TRX_END) {
+                    self.state.set(InternalState::TX_DONE);
+                }
             }
             if interrupt_included(interrupt, IRQ_2_RX_START) {
                 // Start of frame
@@ -345,6 +397,10 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
         // needs to occur before handling the interrupt below.
         if self.state.get() == InternalState::READY {
             self.radio_on.set(true);
+            if self.waking.get() {
+                self.waking.set(false);
+                self.power_client.get().map(|client| client.changed(true));
+            }
         }
 
         // An interrupt can only be pending if an interrupt was fired during an
@@ -356,7 +412,8 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
         if self.interrupt_pending.get() {
             match self.state.get() {
                 InternalState::RX_READING_FRAME_DONE |
-                InternalState::RX_READING_FRAME_FCS_DONE => {}
+                InternalState::RX_READING_FRAME_FCS_DONE |
+                InternalState::RX_READING_FRAME_ED_DONE => {}
                 _ => {
                     self.interrupt_pending.set(false);
                     self.handle_interrupt();
@@ -376,12 +433,32 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
             }
         }
 
+        // An RSSI measurement is started the same way, once any pending
+        // configuration has been committed.
+        if self.rssi_pending.get() && !self.config_pending.get() &&
+           self.state.get() == InternalState::READY {
+            self.state_transition_read(RF233Register::PHY_RSSI, InternalState::RSSI_READ);
+            return;
+        }
+
+        // Finally, the radio only goes to sleep once everything else that is
+        // pending has been taken care of.
+        if self.sleep_pending.get() && !self.config_pending.get() && !self.transmitting.get() &&
+           self.state.get() == InternalState::READY {
+            self.state_transition_write(RF233Register::TRX_STATE,
+                                        RF233TrxCmd::OFF as u8,
+                                        InternalState::SLEEP_TRX_OFF);
+            return;
+        }
+
         match self.state.get() {
             // Default on state; wait for transmit() call or receive interrupt
             InternalState::READY => {}
 
             // Starting state, begin start sequence.
             InternalState::START => {
+                // This is the PART_NUM register read when starting up
+                self.part_num.set(Some(result));
                 self.state_transition_read(RF233Register::IRQ_STATUS,
                                            InternalState::START_PART_READ);
             }
@@ -396,7 +473,7 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                                                 InternalState::START_TURNING_OFF);
                 } else {
                     self.state_transition_write(RF233Register::TRX_CTRL_1,
-                                                TRX_CTRL_1,
+                                                self.trx_ctrl_1(),
                                                 InternalState::START_CTRL1_SET);
                 }
             }
@@ -407,7 +484,7 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 self.irq_pin.enable_interrupt(INTERRUPT_ID, gpio::InterruptMode::RisingEdge);
 
                 self.state_transition_write(RF233Register::TRX_CTRL_1,
-                                            TRX_CTRL_1,
+                                            self.trx_ctrl_1(),
                                             InternalState::START_CTRL1_SET);
             }
             InternalState::START_CTRL1_SET => {
@@ -435,7 +512,7 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
 
             InternalState::START_IRQMASK_SET => {
                 self.state_transition_write(RF233Register::XAH_CTRL_1,
-                                            XAH_CTRL_1,
+                                            self.xah_ctrl_1(),
                                             InternalState::START_XAH1_SET);
             }
 
@@ -443,10 +520,15 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 // This encapsulates the frame retry and CSMA retry
                 // settings in the RF233 C code
                 self.state_transition_write(RF233Register::XAH_CTRL_0,
-                                            XAH_CTRL_0,
+                                            self.xah_ctrl_0(),
                                             InternalState::START_XAH0_SET);
             }
             InternalState::START_XAH0_SET => {
+                self.state_transition_write(RF233Register::CSMA_BE,
+                                            self.csma_be(),
+                                            InternalState::START_CSMA_BE_SET);
+            }
+            InternalState::START_CSMA_BE_SET => {
                 self.state_transition_write(RF233Register::PAN_ID_0,
                                             (self.pan.get() >> 8) as u8,
                                             InternalState::START_PANID0_SET);
@@ -535,9 +617,10 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 // We've completed the SPI operation to read the
                 // IRQ_STATUS register, triggered by an interrupt
                 // denoting moving to the PLL_ON state, so move
-                // to RX_ON (see Sec 7, pg 36 of RF233 datasheet
+                // to RX_ON (see Sec 7, pg 36 of RF233 datasheet), unless the
+                // receiver should stay off
                 self.state_transition_write(RF233Register::TRX_STATE,
-                                            RF233TrxCmd::RX_AACK_ON as u8,
+                                            self.idle_trx_cmd(),
                                             InternalState::READY);
             }
             InternalState::TX_STATUS_PRECHECK1 => {
@@ -600,7 +683,7 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
             }
             InternalState::TX_DONE => {
                 self.state_transition_write(RF233Register::TRX_STATE,
-                                            RF233TrxCmd::RX_AACK_ON as u8,
+                                            self.idle_trx_cmd(),
                                             InternalState::TX_READ_ACK);
             }
             InternalState::TX_READ_ACK => {
@@ -610,15 +693,31 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
 
             // Insert read of TRX_STATUS here, checking TRAC
             InternalState::TX_RETURN_TO_RX => {
-                let ack: bool = (result & TRX_TRAC_MASK) == 0;
-                if status == ExternalState::RX_AACK_ON as u8 {
+                let trac = (result & TRX_TRAC_MASK) >> TRX_TRAC_POS;
+                // An acknowledgement with the frame pending bit set is still
+                // an acknowledgement
+                let ack: bool = trac == 0 || trac == TRX_TRAC_SUCCESS_DATA_PENDING;
+                self.ack_frame_pending.set(trac == TRX_TRAC_SUCCESS_DATA_PENDING);
+                // The CSMA-CA procedure of TX_ARET only fails if every CCA
+                // found the channel busy
+                self.last_cca.set(Some(trac != TRX_TRAC_CHANNEL_ACCESS_FAILURE));
+                let idle = if self.rx_listening.get() {
+                    ExternalState::RX_AACK_ON
+                } else {
+                    ExternalState::PLL_ON
+                };
+                if status == idle as u8 {
                     self.transmitting.set(false);
                     let buf = self.tx_buf.take();
                     self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
 
+                    let underrun = self.tx_underrun.get();
+                    self.tx_underrun.set(false);
                     self.tx_client
                         .get()
-                        .map(|c| { c.send_done(buf.unwrap(), ack, ReturnCode::SUCCESS); });
+                        .map(|c| {
+                            c.send_done(buf.unwrap(), ack, underrun, ReturnCode::SUCCESS);
+                        });
                 } else {
                     self.register_read(RF233Register::TRX_STATUS);
                 }
@@ -631,6 +730,28 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
             // handlers.
             InternalState::TX_PENDING => {}
 
+            // The transmission was aborted while the radio may already have
+            // been preparing to send it, so make sure it sends nothing
+            InternalState::TX_ABORTING => {
+                self.state_transition_write(RF233Register::TRX_STATE,
+                                            RF233TrxCmd::FORCE_TRX_OFF as u8,
+                                            InternalState::TX_ABORT_OFF);
+            }
+            InternalState::TX_ABORT_OFF => {
+                self.state_transition_write(RF233Register::TRX_STATE,
+                                            self.idle_trx_cmd(),
+                                            InternalState::TX_ABORT_DONE);
+            }
+            InternalState::TX_ABORT_DONE => {
+                // A frame handed over since the abort is sent right away
+                if self.transmitting.get() {
+                    self.state_transition_read(RF233Register::TRX_STATUS,
+                                               InternalState::TX_STATUS_PRECHECK1);
+                } else {
+                    self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
+                }
+            }
+
             // No operations in the RX state, an SFD interrupt should
             // take us out of it.
             InternalState::RX => {}
@@ -650,14 +771,20 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 // 1-byte PHY header, which is the length of the frame.
                 // Then, the frame follows, and there are 3 more bytes at the
                 // end corresponding to LQI, ED, and RX_STATUS. Performing a
-                // shorter frame read just drops these bytes.
+                // shorter frame read just drops these bytes, so the LQI is
+                // only read if it still fits in the receive buffer.
                 let frame_len = result;
                 // If the packet isn't too long to fit in the SPI buffer, read it
                 if (frame_len <= radio::MAX_FRAME_SIZE as u8 &&
                     frame_len >= radio::MIN_FRAME_SIZE as u8) {
                     self.state.set(InternalState::RX_READING_FRAME);
                     let rbuf = self.rx_buf.take().unwrap();
-                    self.frame_read(rbuf, frame_len);
+                    let read_len = if radio::PSDU_OFFSET + (frame_len as usize) < rbuf.len() {
+                        frame_len + 1
+                    } else {
+                        frame_len
+                    };
+                    self.frame_read(rbuf, read_len);
                 } else if self.transmitting.get() {
                     // Packet was too long and a transmission is pending,
                     // start the transmission
@@ -676,7 +803,16 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                                            InternalState::RX_READING_FRAME_FCS_DONE);
             }
             InternalState::RX_READING_FRAME_FCS_DONE => {
-                let crc_valid = result & PHY_RSSI_RX_CRC_VALID != 0;
+                self.rx_crc_valid.set(result & PHY_RSSI_RX_CRC_VALID != 0);
+                // The radio measures the energy of each received frame into
+                // PHY_ED_LEVEL, starting at its SHR
+                self.state_transition_read(RF233Register::PHY_ED_LEVEL,
+                                           InternalState::RX_READING_FRAME_ED_DONE);
+            }
+            InternalState::RX_READING_FRAME_ED_DONE => {
+                let crc_valid = self.rx_crc_valid.get();
+                // The ED level is reported in steps of 1 dB above RSSI_BASE_VAL
+                self.rx_rssi.set(RSSI_BASE_VAL.saturating_add(min(result, 0x7f) as i8));
                 self.receiving.set(false);
                 // Just read a packet: if a transmission is pending,
                 // start the transmission state machine
@@ -688,6 +824,12 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 }
                 self.rx_client.get().map(|client| {
                     let rbuf = self.rx_buf.take().unwrap();
+                    let lqi_index = radio::PSDU_OFFSET + rbuf[1] as usize;
+                    self.rx_lqi.set(if lqi_index < rbuf.len() {
+                        rbuf[lqi_index]
+                    } else {
+                        radio::LQI_UNKNOWN
+                    });
                     let frame_len = rbuf[1] as usize - radio::MFR_SIZE;
                     client.receive(rbuf, frame_len, crc_valid, ReturnCode::SUCCESS);
                 });
@@ -758,14 +900,97 @@ impl<'a, S: spi::SpiMasterDevice + 'a> spi::SpiMasterClient for RF233<'a, S> {
                 let val = self.channel.get() | PHY_CC_CCA_MODE_CS_OR_ED;
                 self.state_transition_write(RF233Register::PHY_CC_CCA,
                                             val,
+                                            InternalState::CONFIG_CCA_SET);
+            }
+            InternalState::CONFIG_CCA_SET => {
+                self.state_transition_write(RF233Register::TRX_CTRL_1,
+                                            self.trx_ctrl_1(),
+                                            InternalState::CONFIG_CTRL1_SET);
+            }
+            InternalState::CONFIG_CTRL1_SET => {
+                let mut val = CSMA_SEED_1;
+                if self.pan_coordinator.get() {
+                    val |= CSMA_SEED_1_AACK_I_AM_COORD;
+                }
+                // Frames are never acknowledged in listen-only mode, and not
+                // in promiscuous mode either, where they may be foreign
+                if !self.auto_ack.get() || self.listen_only.get() || self.promiscuous.get() {
+                    val |= CSMA_SEED_1_AACK_DIS_ACK;
+                }
+                self.state_transition_write(RF233Register::CSMA_SEED_1,
+                                            val,
+                                            InternalState::CONFIG_SEED1_SET);
+            }
+            InternalState::CONFIG_SEED1_SET => {
+                let val = match self.sensitivity.get() {
+                    radio::SensitivityMode::HighDynamicRange => {
+                        RX_SYN_RX_PDT_LEVEL_HIGH_DYNAMIC_RANGE
+                    }
+                    _ => RX_SYN_RX_PDT_LEVEL_MAX_SENSITIVITY,
+                };
+                self.state_transition_write(RF233Register::RX_SYN,
+                                            val,
+                                            InternalState::CONFIG_RX_SYN_SET);
+            }
+            InternalState::CONFIG_RX_SYN_SET => {
+                self.state_transition_write(RF233Register::XAH_CTRL_1,
+                                            self.xah_ctrl_1(),
+                                            InternalState::CONFIG_XAH1_SET);
+            }
+            InternalState::CONFIG_XAH1_SET => {
+                self.state_transition_write(RF233Register::XAH_CTRL_0,
+                                            self.xah_ctrl_0(),
+                                            InternalState::CONFIG_XAH0_SET);
+            }
+            InternalState::CONFIG_XAH0_SET => {
+                self.state_transition_write(RF233Register::CSMA_BE,
+                                            self.csma_be(),
                                             InternalState::CONFIG_DONE);
             }
 
             InternalState::CONFIG_DONE => {
                 self.config_pending.set(false);
-                self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
+                if self.rx_listening.get() != self.rx_on_when_idle.get() && !self.receiving.get() {
+                    // Turn the receiver on or off as configured
+                    self.state_transition_write(RF233Register::TRX_STATE,
+                                                self.idle_trx_cmd(),
+                                                InternalState::READY);
+                } else {
+                    self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
+                }
                 self.cfg_client.get().map(|c| { c.config_done(ReturnCode::SUCCESS); });
             }
+
+            InternalState::RSSI_READ => {
+                self.rssi_pending.set(false);
+                // The RSSI is reported in steps of 3 dB above RSSI_BASE_VAL
+                let rssi = RSSI_BASE_VAL + 3 * ((result & PHY_RSSI_RSSI_MASK) as i8);
+                self.state_transition_read(RF233Register::TRX_STATUS, InternalState::READY);
+                self.measurement_client.get().map(|c| { c.rssi_done(rssi, ReturnCode::SUCCESS); });
+            }
+
+            InternalState::SLEEP_TRX_OFF => {
+                // Once the transceiver is off, raising SLP_TR puts the radio
+                // to sleep
+                self.sleep_pending.set(false);
+                self.sleep_pin.set();
+                self.radio_on.set(false);
+                self.state.set(InternalState::SLEEP);
+                self.power_client.get().map(|client| client.changed(false));
+            }
+            InternalState::SLEEP => {}
+            InternalState::WAKE_STATUS_READ => {
+                if status == ExternalState::TRX_OFF as u8 {
+                    // Awake, so turn the radio back on the same way as after
+                    // starting it
+                    self.state_transition_write(RF233Register::TRX_STATE,
+                                                RF233TrxCmd::PLL_ON as u8,
+                                                InternalState::ON_PLL_WAITING);
+                } else {
+                    self.state_transition_read(RF233Register::TRX_STATUS,
+                                               InternalState::WAKE_STATUS_READ);
+                }
+            }
         }
     }
 }
@@ -799,6 +1024,13 @@ impl<'a, S: spi::SpiMasterDevice + 'a> RF233<'a, S> {
             interrupt_handling: Cell::new(false),
             interrupt_pending: Cell::new(false),
             config_pending: Cell::new(false),
+            rssi_pending: Cell::new(false),
+            sleep_pending: Cell::new(false),
+            waking: Cell::new(false),
+            tx_underrun: Cell::new(false),
+            rx_crc_valid: Cell::new(false),
+            rx_rssi: Cell::new(RSSI_BASE_VAL),
+            rx_lqi: Cell::new(radio::LQI_UNKNOWN),
             tx_buf: TakeCell::empty(),
             rx_buf: TakeCell::empty(),
             tx_len: Cell::new(0),
@@ -806,17 +1038,63 @@ impl<'a, S: spi::SpiMasterDevice + 'a> RF233<'a, S> {
             rx_client: Cell::new(None),
             cfg_client: Cell::new(None),
             power_client: Cell::new(None),
+            measurement_client: Cell::new(None),
             addr: Cell::new(0),
             addr_long: Cell::new([0x00; 8]),
             pan: Cell::new(0),
             tx_power: Cell::new(setting_to_power(PHY_TX_PWR)),
             channel: Cell::new(PHY_CHANNEL),
+            auto_crc: Cell::new(true),
+            sensitivity: Cell::new(radio::SensitivityMode::HighSensitivity),
+            pan_coordinator: Cell::new(false),
+            listen_only: Cell::new(false),
+            promiscuous: Cell::new(true),
+            auto_ack: Cell::new(true),
+            rx_on_when_idle: Cell::new(true),
+            rx_listening: Cell::new(true),
+            min_be: Cell::new(CSMA_BE & CSMA_BE_MIN_BE_MASK),
+            max_be: Cell::new(CSMA_BE >> CSMA_BE_MAX_BE_POS),
+            max_csma_retries: Cell::new((XAH_CTRL_0 & XAH_CTRL_0_MAX_CSMA_RETRIES_MASK) >>
+                                        XAH_CTRL_0_MAX_CSMA_RETRIES_POS),
+            last_cca: Cell::new(None),
+            ack_frame_pending: Cell::new(false),
+            part_num: Cell::new(None),
             spi_rx: TakeCell::empty(),
             spi_tx: TakeCell::empty(),
             spi_buf: TakeCell::empty(),
         }
     }
 
+    /// The TRX_CTRL_1 setting, with automatic FCS generation as configured.
+    fn trx_ctrl_1(&self) -> u8 {
+        if self.auto_crc.get() {
+            TRX_CTRL_1
+        } else {
+            TRX_CTRL_1 & !TRX_CTRL_1_AUTO_CRC
+        }
+    }
+
+    /// The XAH_CTRL_0 setting, with the number of CSMA-CA retries as
+    /// configured.
+    fn xah_ctrl_0(&self) -> u8 {
+        (XAH_CTRL_0 & !XAH_CTRL_0_MAX_CSMA_RETRIES_MASK) |
+        (self.max_csma_retries.get() << XAH_CTRL_0_MAX_CSMA_RETRIES_POS)
+    }
+
+    /// The CSMA_BE setting, with the backoff exponents as configured.
+    fn csma_be(&self) -> u8 {
+        (self.max_be.get() << CSMA_BE_MAX_BE_POS) | self.min_be.get()
+    }
+
+    /// The XAH_CTRL_1 setting, with promiscuous mode as configured.
+    fn xah_ctrl_1(&self) -> u8 {
+        if self.promiscuous.get() {
+            XAH_CTRL_1
+        } else {
+            XAH_CTRL_1 & !XAH_CTRL_1_AACK_PROM_MODE
+        }
+    }
+
     fn handle_interrupt(&self) {
         // Because the first thing we do on handling an interrupt is
         // read the IRQ status, we defer handling the state transition
@@ -894,6 +1172,17 @@ impl<'a, S: spi::SpiMasterDevice + 'a> RF233<'a, S> {
         self.state.set(state);
         self.register_read(reg);
     }
+
+    // The TRX_STATE command that makes the radio idle: RX_AACK_ON, or PLL_ON
+    // if the receiver stays off while idle. Records which one was used.
+    fn idle_trx_cmd(&self) -> u8 {
+        self.rx_listening.set(self.rx_on_when_idle.get());
+        if self.rx_on_when_idle.get() {
+            RF233TrxCmd::RX_AACK_ON as u8
+        } else {
+            RF233TrxCmd::PLL_ON as u8
+        }
+    }
 }
 
 impl<'a, S: spi::SpiMasterDevice + 'a> radio::Radio for RF233<'a, S> {}
@@ -929,7 +1218,18 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
     }
 
     fn start(&self) -> ReturnCode {
-        if self.state.get() != InternalState::START {
+        if self.state.get() == InternalState::SLEEP {
+            // Lowering SLP_TR wakes the radio up into TRX_OFF
+            self.waking.set(true);
+            self.sleep_pin.clear();
+            self.state_transition_read(RF233Register::TRX_STATUS,
+                                       InternalState::WAKE_STATUS_READ);
+            return ReturnCode::SUCCESS;
+        } else if self.sleep_pending.get() {
+            // The radio has not gone to sleep yet, so just stay on
+            self.sleep_pending.set(false);
+            return ReturnCode::EALREADY;
+        } else if self.state.get() != InternalState::START {
             return ReturnCode::EALREADY;
         }
         self.register_read(RF233Register::PART_NUM);
@@ -937,8 +1237,20 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
     }
 
     fn stop(&self) -> ReturnCode {
-        // XXX: TODO: implement stop()
-        ReturnCode::ENOSUPPORT
+        if !self.radio_on.get() || self.sleep_pending.get() {
+            return ReturnCode::EALREADY;
+        } else if self.transmitting.get() || self.receiving.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.sleep_pending.set(true);
+        if !self.spi_busy.get() && !self.config_pending.get() && !self.rssi_pending.get() &&
+           self.state.get() == InternalState::READY {
+            self.state_transition_write(RF233Register::TRX_STATE,
+                                        RF233TrxCmd::OFF as u8,
+                                        InternalState::SLEEP_TRX_OFF);
+        }
+        // Otherwise, the pending flag is checked on return to READY
+        ReturnCode::SUCCESS
     }
 
     fn is_on(&self) -> bool {
@@ -978,6 +1290,61 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
         }
     }
 
+    fn set_auto_crc(&self, enabled: bool) {
+        self.auto_crc.set(enabled);
+    }
+
+    fn set_pan_coordinator(&self, coordinator: bool) {
+        self.pan_coordinator.set(coordinator);
+    }
+
+    fn set_listen_only(&self, listen_only: bool) {
+        self.listen_only.set(listen_only);
+    }
+
+    fn set_promiscuous(&self, promiscuous: bool) {
+        self.promiscuous.set(promiscuous);
+    }
+
+    fn set_auto_ack(&self, auto_ack: bool) {
+        self.auto_ack.set(auto_ack);
+    }
+
+    fn set_rx_on_when_idle(&self, rx_on: bool) {
+        self.rx_on_when_idle.set(rx_on);
+    }
+
+    fn set_csma_params(&self, min_be: u8, max_be: u8, max_retries: u8) -> ReturnCode {
+        if min_be > max_be || max_be > radio::MAX_BE || max_retries > radio::MAX_CSMA_RETRIES {
+            return ReturnCode::EINVAL;
+        }
+        self.min_be.set(min_be);
+        self.max_be.set(max_be);
+        self.max_csma_retries.set(max_retries);
+        ReturnCode::SUCCESS
+    }
+
+    /// The RF233 has no manual gain control. It defaults to high sensitivity.
+    fn set_sensitivity_mode(&self, mode: radio::SensitivityMode) -> ReturnCode {
+        match mode {
+            radio::SensitivityMode::ManualGain => ReturnCode::ENOSUPPORT,
+            _ => {
+                self.sensitivity.set(mode);
+                ReturnCode::SUCCESS
+            }
+        }
+    }
+
+    /// The RF233 has no loopback mode, although disabling loopback trivially
+    /// succeeds.
+    fn set_loopback(&self, loopback: bool) -> ReturnCode {
+        if loopback {
+            ReturnCode::ENOSUPPORT
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
     fn set_channel(&self, chan: u8) -> ReturnCode {
         if chan >= 11 && chan <= 26 {
             self.channel.set(chan);
@@ -995,6 +1362,18 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
         self.addr_long.get()
     }
 
+    /// The RF233 is not programmed with an EUI-64; boards have to supply
+    /// their own from another chip.
+    fn get_eui64(&self) -> Option<[u8; 8]> {
+        None
+    }
+
+    /// The PART_NUM register, which reads 0x0B on the RF233, once the radio
+    /// has started up.
+    fn get_chip_id(&self) -> Option<u32> {
+        self.part_num.get().map(|part| part as u32)
+    }
+
     /// The 16-bit PAN ID
     fn get_pan(&self) -> u16 {
         self.pan.get()
@@ -1007,6 +1386,60 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioConfig for RF233<'a, S> {
     fn get_channel(&self) -> u8 {
         self.channel.get()
     }
+    /// The symbol rate of the PHY mode, which is always 250 kb/s O-QPSK
+    fn get_symbol_rate(&self) -> u32 {
+        SYMBOL_RATE_250
+    }
+    /// The number of frame retransmissions configured in XAH_CTRL_0
+    fn get_max_retries(&self) -> u8 {
+        (XAH_CTRL_0 & XAH_CTRL_0_MAX_FRAME_RETRIES_MASK) >> XAH_CTRL_0_MAX_FRAME_RETRIES_POS
+    }
+
+    /// Everything but loopback, which the RF233 lacks
+    fn get_capabilities(&self) -> u32 {
+        radio::CAP_EXTENDED_ADDRESSING | radio::CAP_AUTO_ACK | radio::CAP_FRAME_RETRIES |
+        radio::CAP_TX_POWER | radio::CAP_PROMISCUOUS | radio::CAP_SLEEP
+    }
+
+    /// The outcome of the CSMA-CA procedure of the last transmission
+    fn get_last_cca(&self) -> Option<bool> {
+        self.last_cca.get()
+    }
+
+    fn get_ack_frame_pending(&self) -> bool {
+        self.ack_frame_pending.get()
+    }
+
+    fn get_power_state(&self) -> radio::PowerState {
+        if !self.radio_on.get() {
+            radio::PowerState::Sleep
+        } else if self.transmitting.get() {
+            radio::PowerState::Tx
+        } else if self.receiving.get() || self.rx_listening.get() {
+            radio::PowerState::Rx
+        } else {
+            radio::PowerState::Idle
+        }
+    }
+
+    fn read_rssi(&self) -> ReturnCode {
+        if !self.radio_on.get() {
+            return ReturnCode::EOFF;
+        } else if self.rssi_pending.get() {
+            return ReturnCode::EBUSY;
+        }
+        self.rssi_pending.set(true);
+        if !self.spi_busy.get() && !self.config_pending.get() &&
+           self.state.get() == InternalState::READY {
+            self.state_transition_read(RF233Register::PHY_RSSI, InternalState::RSSI_READ);
+        }
+        // Otherwise, the pending flag is checked on return to READY
+        ReturnCode::SUCCESS
+    }
+
+    fn set_measurement_client(&self, client: &'static radio::MeasurementClient) {
+        self.measurement_client.set(Some(client));
+    }
 
     fn config_commit(&self) {
         let pending = self.config_pending.get();
@@ -1043,6 +1476,18 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioData for RF233<'a, S> {
         self.rx_buf.replace(buffer);
     }
 
+    fn has_receive_buffer(&self) -> bool {
+        self.rx_buf.is_some()
+    }
+
+    fn get_rx_rssi(&self) -> i8 {
+        self.rx_rssi.get()
+    }
+
+    fn get_rx_lqi(&self) -> u8 {
+        self.rx_lqi.get()
+    }
+
     // The payload length is the length of the MAC payload, not the PSDU
     fn transmit(&self,
                 spi_buf: &'static mut [u8],
@@ -1052,6 +1497,8 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioData for RF233<'a, S> {
         let frame_len = frame_len + radio::MFR_SIZE;
         if !self.radio_on.get() {
             return (ReturnCode::EOFF, Some(spi_buf));
+        } else if self.listen_only.get() {
+            return (ReturnCode::ENOSUPPORT, Some(spi_buf));
         } else if self.tx_buf.is_some() || self.transmitting.get() {
             return (ReturnCode::EBUSY, Some(spi_buf));
         } else if radio::PSDU_OFFSET + frame_len >= spi_buf.len() {
@@ -1070,4 +1517,53 @@ impl<'a, S: spi::SpiMasterDevice + 'a> radio::RadioData for RF233<'a, S> {
         }
         return (ReturnCode::SUCCESS, None);
     }
+
+    fn abort_transmit(&self) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let state = self.state.get();
+        if !self.transmitting.get() {
+            return (ReturnCode::EALREADY, None);
+        }
+        match state {
+            // The frame is being written into the radio, or TRX_END has
+            // already been seen and the frame is completing
+            InternalState::TX_WRITING_FRAME |
+            InternalState::TX_DONE |
+            InternalState::TX_READ_ACK |
+            InternalState::TX_RETURN_TO_RX => {
+                return (ReturnCode::EBUSY, None);
+            }
+            _ => {}
+        }
+        let buf = match self.tx_buf.take() {
+            Some(buf) => buf,
+            None => return (ReturnCode::EBUSY, None),
+        };
+        self.transmitting.set(false);
+        self.tx_underrun.set(false);
+        match state {
+            // The radio is still receiving, so only the pending register read
+            // remains, which now ends in READY
+            InternalState::TX_STATUS_PRECHECK1 |
+            InternalState::TX_STATUS_PRECHECK2 => {
+                self.state.set(InternalState::READY);
+            }
+            // The radio is on its way to sending the frame
+            InternalState::TX_PLL_START |
+            InternalState::TX_PLL_WAIT |
+            InternalState::TX_ARET_ON |
+            InternalState::TX_TRANSMITTING => {
+                if self.spi_busy.get() {
+                    self.state.set(InternalState::TX_ABORTING);
+                } else {
+                    self.state_transition_write(RF233Register::TRX_STATE,
+                                                RF233TrxCmd::FORCE_TRX_OFF as u8,
+                                                InternalState::TX_ABORT_OFF);
+                }
+            }
+            // Otherwise the transmission has not started, and is only
+            // started later if `transmitting` is still set
+            _ => {}
+        }
+        (ReturnCode::SUCCESS, Some(buf))
+    }
 }
@@ -69,13 +69,28 @@ pub const PHY_CC_CCA_MODE_ED: u8 = 1 << 5;
 pub const PHY_CC_CCA_MODE_CS: u8 = 2 << 5;
 pub const PHY_CC_CCA_MODE_CS_AND_ED: u8 = 3 << 5;
 pub const PHY_RSSI_RX_CRC_VALID: u8 = 1 << 7;
+pub const PHY_RSSI_RSSI_MASK: u8 = 0x1F;
+pub const RSSI_BASE_VAL: i8 = -94;
 pub const TRX_CTRL_2_RX_SAFE_MODE: u8 = 1 << 7;
 pub const TRX_CTRL_2_DATA_RATE_250: u8 = 0;
+pub const SYMBOL_RATE_250: u32 = 62500;
 pub const IRQ_TRXBUF_ACCESS_VIOLATION: u8 = 1 << 6;
 pub const IRQ_TRX_DONE: u8 = 1 << 3;
 pub const IRQ_RX_START: u8 = 1 << 2;
 pub const IRQ_PLL_LOCK: u8 = 1 << 0;
 pub const XAH_CTRL_1_AACK_PROM_MODE: u8 = 1 << 1;
+pub const CSMA_SEED_1_AACK_I_AM_COORD: u8 = 1 << 3;
+pub const CSMA_SEED_1_AACK_DIS_ACK: u8 = 1 << 4;
+// Receiver desensitization threshold: 0 is the maximum sensitivity, and each
+// level above 0 ignores signals weaker than about -101 dBm plus 3 dB per level
+pub const RX_SYN_RX_PDT_LEVEL_MAX_SENSITIVITY: u8 = 0;
+pub const RX_SYN_RX_PDT_LEVEL_HIGH_DYNAMIC_RANGE: u8 = 7;
+pub const XAH_CTRL_0_MAX_FRAME_RETRIES_POS: u8 = 4;
+pub const XAH_CTRL_0_MAX_FRAME_RETRIES_MASK: u8 = 0xF << XAH_CTRL_0_MAX_FRAME_RETRIES_POS;
+pub const XAH_CTRL_0_MAX_CSMA_RETRIES_POS: u8 = 1;
+pub const XAH_CTRL_0_MAX_CSMA_RETRIES_MASK: u8 = 0x7 << XAH_CTRL_0_MAX_CSMA_RETRIES_POS;
+pub const CSMA_BE_MAX_BE_POS: u8 = 4;
+pub const CSMA_BE_MIN_BE_MASK: u8 = 0xF;
 
 // Flag combinations that are used in initialization.
 pub const TRX_CTRL_1: u8 = (TRX_CTRL_1_DIG34_RXTX_INDICATOR | TRX_CTRL_1_SPI_CMD_TRX_STATUS |
@@ -87,8 +102,14 @@ pub const PHY_CHANNEL: u8 = 26;
 pub const IRQ_MASK: u8 = (IRQ_TRXBUF_ACCESS_VIOLATION | IRQ_TRX_DONE | IRQ_PLL_LOCK | IRQ_RX_START);
 pub const XAH_CTRL_1: u8 = XAH_CTRL_1_AACK_PROM_MODE;
 pub const XAH_CTRL_0: u8 = 0;
+// Reset value: maximum backoff exponent 5, minimum backoff exponent 3
+pub const CSMA_BE: u8 = 0x53;
+// Reset value: accept frame versions 0 and 1, CSMA seed bits 0b010
+pub const CSMA_SEED_1: u8 = 0x42;
 pub const TRX_RPC: u8 = 0xFF;
 pub const TRX_TRAC_MASK: u8 = 0xE0;
+pub const TRX_TRAC_POS: u8 = 5;
+pub const TRX_TRAC_CHANNEL_ACCESS_FAILURE: u8 = 3;
 pub const TRX_TRAC_SUCCESS_DATA_PENDING: u8 = 1;
 
 // Default address settings.
@@ -148,6 +169,7 @@ pub enum ExternalState {
 // radio state.
 pub enum RF233TrxCmd {
     TX_START = 0x02,
+    FORCE_TRX_OFF = 0x03,
     RX_ON = 0x06,
     OFF = 0x08,
     PLL_ON = 0x09,
@@ -9,7 +9,10 @@
 
 use returncode::ReturnCode;
 pub trait TxClient {
-    fn send_done(&self, buf: &'static mut [u8], acked: bool, result: ReturnCode);
+    /// Called when a transmission has completed. `underrun` is true if the
+    /// radio's frame buffer underran during the transmission, so that the
+    /// frame sent on air was corrupt.
+    fn send_done(&self, buf: &'static mut [u8], acked: bool, underrun: bool, result: ReturnCode);
 }
 
 pub trait RxClient {
@@ -28,6 +31,39 @@ pub trait PowerClient {
     fn changed(&self, on: bool);
 }
 
+pub trait MeasurementClient {
+    /// Called when a measurement started by `read_rssi` completes. `rssi` is
+    /// the received signal strength on the current channel, in dBm.
+    fn rssi_done(&self, rssi: i8, result: ReturnCode);
+}
+
+/// A trade-off between the receiver's sensitivity and its immunity to strong
+/// nearby signals.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum SensitivityMode {
+    /// Receive the weakest frames the radio can detect.
+    HighSensitivity,
+    /// Ignore weak signals, so that strong nearby signals are received
+    /// reliably and weak interference does not block reception.
+    HighDynamicRange,
+    /// Use a fixed, manually configured receiver gain.
+    ManualGain,
+}
+
+/// What the radio is currently doing, from the least to the most power it
+/// draws.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum PowerState {
+    /// The radio is off or asleep.
+    Sleep = 0,
+    /// The radio is on, but its receiver is off.
+    Idle = 1,
+    /// The receiver is on, listening for or receiving a frame.
+    Rx = 2,
+    /// A frame is being transmitted.
+    Tx = 3,
+}
+
 /// These constants are used for interacting with the SPI buffer, which contains
 /// a 1-byte SPI command, a 1-byte PHY header, and then the 802.15.4 frame. In
 /// theory, the number of extra bytes in front of the frame can depend on the
@@ -55,6 +91,20 @@ pub const MAX_FRAME_SIZE: usize = MAX_MTU;
 pub const PSDU_OFFSET: usize = 2;
 pub const MAX_BUF_SIZE: usize = PSDU_OFFSET + MAX_MTU;
 pub const MIN_PAYLOAD_OFFSET: usize = PSDU_OFFSET + MIN_MHR_SIZE;
+pub const LQI_UNKNOWN: u8 = 0xff;
+/// Largest CSMA-CA backoff exponent permitted by 802.15.4
+pub const MAX_BE: u8 = 8;
+/// Largest number of CSMA-CA retries permitted by 802.15.4
+pub const MAX_CSMA_RETRIES: u8 = 5;
+
+/// Bits of the bitmask returned by `RadioConfig::get_capabilities`
+pub const CAP_EXTENDED_ADDRESSING: u32 = 1 << 0; // Filters on the 64-bit address
+pub const CAP_AUTO_ACK: u32 = 1 << 1; // .......... Acknowledges frames itself
+pub const CAP_FRAME_RETRIES: u32 = 1 << 2; // ..... Retransmits unacked frames
+pub const CAP_LOOPBACK: u32 = 1 << 3; // .......... Supports `set_loopback`
+pub const CAP_TX_POWER: u32 = 1 << 4; // .......... Adjustable transmit power
+pub const CAP_PROMISCUOUS: u32 = 1 << 5; // ....... Supports `set_promiscuous`
+pub const CAP_SLEEP: u32 = 1 << 6; // ............. Sleeps on `stop`
 
 pub trait Radio: RadioConfig + RadioData {}
 
@@ -68,7 +118,13 @@ pub trait RadioConfig {
                   reg_read: &'static mut [u8])
                   -> ReturnCode;
     fn reset(&self) -> ReturnCode;
+    /// Turn the radio on, or wake it up after `stop`. Returns EALREADY if the
+    /// radio is already on, otherwise the power client is notified once the
+    /// radio is on.
     fn start(&self) -> ReturnCode;
+    /// Put the radio to sleep once it is idle. Returns EALREADY if the radio
+    /// is already off and EBUSY while it is transmitting or receiving a frame,
+    /// otherwise the power client is notified once the radio is off.
     fn stop(&self) -> ReturnCode;
     fn is_on(&self) -> bool;
     fn busy(&self) -> bool;
@@ -83,24 +139,102 @@ pub trait RadioConfig {
 
     fn get_address(&self) -> u16; //....... The local 16-bit address
     fn get_address_long(&self) -> [u8; 8]; // 64-bit address
+    /// The factory-programmed EUI-64 of the radio, or `None` if it has none.
+    /// Unlike the long address, this cannot be changed.
+    fn get_eui64(&self) -> Option<[u8; 8]>;
+    /// An identifier of the radio chip, such as its part number, or `None`
+    /// if the radio does not report one.
+    fn get_chip_id(&self) -> Option<u32>;
     fn get_pan(&self) -> u16; //........... The 16-bit PAN ID
     fn get_tx_power(&self) -> i8; //....... The transmit power, in dBm
     fn get_channel(&self) -> u8; // ....... The 802.15.4 channel
+    fn get_symbol_rate(&self) -> u32; // .. PHY symbols per second
+    fn get_max_retries(&self) -> u8; // ... Frame retransmissions after no ACK
+    fn get_capabilities(&self) -> u32; // . The supported CAP_* features
+    /// Whether the channel was clear at the most recent clear channel
+    /// assessment, or `None` if none has been performed yet.
+    fn get_last_cca(&self) -> Option<bool>;
+    /// Whether the acknowledgement of the most recent transmission had its
+    /// frame pending bit set, meaning the receiver has more data queued for
+    /// this node. Radios that cannot tell always return false.
+    fn get_ack_frame_pending(&self) -> bool;
+    /// What the radio is currently doing.
+    fn get_power_state(&self) -> PowerState;
 
     fn set_address(&self, addr: u16);
     fn set_address_long(&self, addr: [u8; 8]);
     fn set_pan(&self, id: u16);
     fn set_tx_power(&self, power: i8) -> ReturnCode;
     fn set_channel(&self, chan: u8) -> ReturnCode;
+    /// Whether the radio computes the FCS of transmitted frames in hardware.
+    /// When disabled, the last MFR_SIZE bytes of the frame in the transmit
+    /// buffer are sent as the FCS instead. Enabled by default.
+    fn set_auto_crc(&self, enabled: bool);
+    /// Whether the radio acts as the PAN coordinator, accepting frames
+    /// without a destination address.
+    fn set_pan_coordinator(&self, coordinator: bool);
+    /// Whether the radio must never transmit. While enabled, `transmit`
+    /// returns ENOSUPPORT immediately, and after the next `config_commit` the
+    /// radio also stops acknowledging received frames.
+    fn set_listen_only(&self, listen_only: bool);
+    /// Whether the radio receives every frame on the channel, regardless of
+    /// its destination address and PAN ID. While enabled, the radio does not
+    /// acknowledge any received frame. Takes effect after the next
+    /// `config_commit`; disabling it restores filtering by the configured
+    /// addresses and PAN ID. Enabled by default.
+    fn set_promiscuous(&self, promiscuous: bool);
+    /// Whether the radio acknowledges received frames that are addressed to
+    /// it and request an acknowledgement. Enabled by default. Listen-only and
+    /// promiscuous mode disable acknowledgements regardless. Takes effect
+    /// after the next `config_commit`.
+    fn set_auto_ack(&self, auto_ack: bool);
+    /// Whether the receiver stays on while the radio is idle. When disabled,
+    /// the radio only listens for the acknowledgement of its own
+    /// transmissions, and handing it a receive buffer does not turn the
+    /// receiver back on. Enabled by default. Takes effect after the next
+    /// `config_commit`.
+    fn set_rx_on_when_idle(&self, rx_on: bool);
+    /// Set the CSMA-CA minimum and maximum backoff exponents and the number of
+    /// retries after a busy channel before channel access fails. Returns EINVAL unless
+    /// `min_be <= max_be <= MAX_BE` and `max_retries <= MAX_CSMA_RETRIES`.
+    /// Takes effect after the next `config_commit`.
+    fn set_csma_params(&self, min_be: u8, max_be: u8, max_retries: u8) -> ReturnCode;
+    /// Select the receiver's sensitivity mode. Returns ENOSUPPORT if the radio
+    /// does not support the mode.
+    fn set_sensitivity_mode(&self, mode: SensitivityMode) -> ReturnCode;
+    /// Whether transmitted frames are looped back to the receiver instead of
+    /// going over the air, for self-tests. Takes effect immediately. Returns
+    /// ENOSUPPORT if the radio has no loopback mode.
+    fn set_loopback(&self, loopback: bool) -> ReturnCode;
+
+    /// Start an asynchronous measurement of the received signal strength on
+    /// the current channel, issuing a callback to the measurement client when
+    /// done.
+    fn read_rssi(&self) -> ReturnCode;
+    fn set_measurement_client(&self, client: &'static MeasurementClient);
 }
 
 pub trait RadioData {
     fn set_transmit_client(&self, client: &'static TxClient);
     fn set_receive_client(&self, client: &'static RxClient, receive_buffer: &'static mut [u8]);
     fn set_receive_buffer(&self, receive_buffer: &'static mut [u8]);
+    /// Whether the radio currently holds a buffer to receive frames into.
+    fn has_receive_buffer(&self) -> bool;
+    /// The received signal strength of the most recently received frame, in
+    /// dBm. This is valid for the frame passed to `RxClient::receive`.
+    fn get_rx_rssi(&self) -> i8;
+    /// The link quality indication of the most recently received frame,
+    /// from 0 to 255, or `LQI_UNKNOWN` if the radio could not determine it.
+    fn get_rx_lqi(&self) -> u8;
 
     fn transmit(&self,
                 spi_buf: &'static mut [u8],
                 frame_len: usize)
                 -> (ReturnCode, Option<&'static mut [u8]>);
+    /// Stop the transmission started by `transmit`, unless the frame has
+    /// already been sent, and return its buffer. No `send_done` callback
+    /// follows for an aborted frame. Returns EALREADY if no frame is being
+    /// transmitted, and EBUSY if the frame can no longer be stopped, in which
+    /// case it completes as usual.
+    fn abort_transmit(&self) -> (ReturnCode, Option<&'static mut [u8]>);
 }